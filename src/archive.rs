@@ -0,0 +1,824 @@
+use std::collections::HashSet;
+use std::io::{Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use log::{debug, info, warn};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::error::Result;
+use crate::snapshot::Kind;
+use crate::utils::confirm;
+
+/// Expand a list of globs into the absolute paths they match.
+pub fn resolve_globs(globs: &[String]) -> Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    for g in globs.iter() {
+        for file in glob::glob(g)? {
+            files.push(std::fs::canonicalize(file?)?);
+        }
+    }
+    Ok(files)
+}
+
+/// The name a path is stored under in an archive. Like `tar`, the leading `/` is dropped.
+pub(crate) fn archive_name(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|c| !matches!(c, Component::RootDir | Component::Prefix(_)))
+        .collect()
+}
+
+/// Where the entries of a backup are restored to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Target {
+    /// The directory entries are restored under, `/` to put them back where they came from.
+    pub root: PathBuf,
+    /// A prefix to remove from the names of entries before restoring them. Entries outside of
+    /// it are not restored.
+    pub strip_prefix: Option<PathBuf>,
+    /// What to do about files that are already there.
+    pub overwrite: Overwrite,
+}
+
+/// What to do when a file being restored already exists.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Overwrite {
+    #[default]
+    Always,
+    Never,
+    /// Only overwrite files that are older than the ones in the backup.
+    IfNewer,
+    /// Rename existing files out of the way, adding `.orig` to their names.
+    Backup,
+    /// Ask on the terminal for each file.
+    Ask,
+}
+
+/// What restoring an entry, or removing a file that a backup recorded as deleted, does to
+/// whatever is already at its path.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    /// Go ahead, since nothing is in the way, or only something restored earlier.
+    Restore,
+    /// Restore the contents of a directory into the existing directory.
+    Merge,
+    /// Overwrite or remove the existing file.
+    Replace,
+    /// Leave the existing file alone.
+    Skip,
+    /// Rename the existing file to the given name first.
+    Preserve(PathBuf),
+    /// Ask on the terminal whether to overwrite or remove the existing file.
+    Ask,
+}
+
+/// What happened to the files that were already there when a backup was restored.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Report {
+    /// Existing files that were overwritten or removed.
+    pub replaced: Vec<PathBuf>,
+    /// Existing files that were left alone.
+    pub skipped: Vec<PathBuf>,
+    /// Existing files that were renamed out of the way, and what they were renamed to.
+    pub preserved: Vec<(PathBuf, PathBuf)>,
+    /// Everything restored so far. Later increments replace these without any fuss.
+    restored: HashSet<PathBuf>,
+}
+
+impl Report {
+    /// Record what a restore planned with `Target::decide` would have done to `path`, so that
+    /// later increments are planned as if it had.
+    pub fn plan(&mut self, path: &Path, action: &Action) {
+        if *action != Action::Skip {
+            self.restored.insert(path.to_path_buf());
+        }
+    }
+
+    /// Record that a restore planned with `Target::decide_removal` would have dealt with `path`.
+    pub fn plan_removal(&mut self, path: &Path) {
+        self.restored.remove(path);
+    }
+}
+
+/// Find a name to move an existing file to: `.orig` added to its name, then `.orig.1` and so on.
+fn backup_name(path: &Path) -> PathBuf {
+    let mut attempt = 0;
+    loop {
+        let mut name = path.as_os_str().to_owned();
+        match attempt {
+            0 => name.push(".orig"),
+            _ => name.push(format!(".orig.{}", attempt)),
+        }
+        let name = PathBuf::from(name);
+        if std::fs::symlink_metadata(&name).is_err() {
+            return name;
+        }
+        attempt += 1;
+    }
+}
+
+impl Target {
+    pub fn new(root: &Path) -> Target {
+        Target {
+            root: root.to_path_buf(),
+            strip_prefix: None,
+            overwrite: Overwrite::Always,
+        }
+    }
+
+    /// Decide what restoring an entry to `path` does to whatever is already there, without
+    /// doing any of it. Existing directories are merged with the directories in the backup rather
+    /// than replaced.
+    pub fn decide(
+        &self,
+        path: &Path,
+        is_dir: bool,
+        mtime: SystemTime,
+        report: &Report,
+    ) -> Result<Action> {
+        if report.restored.contains(path) {
+            return Ok(Action::Restore);
+        }
+        let existing = match std::fs::symlink_metadata(path) {
+            Ok(existing) => existing,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Action::Restore),
+            Err(e) => return Err(e.into()),
+        };
+        if is_dir && existing.is_dir() {
+            return Ok(Action::Merge);
+        }
+        Ok(match self.overwrite {
+            Overwrite::Always => Action::Replace,
+            Overwrite::Never => Action::Skip,
+            Overwrite::IfNewer if existing.modified()? < mtime => Action::Replace,
+            Overwrite::IfNewer => Action::Skip,
+            Overwrite::Backup => Action::Preserve(backup_name(path)),
+            Overwrite::Ask => Action::Ask,
+        })
+    }
+
+    /// Decide what to do about a file that a backup recorded as deleted, or None if there is
+    /// nothing at `path` to remove. Files restored earlier are always removed, but existing files
+    /// are only removed if the policy allows overwriting them unconditionally.
+    pub fn decide_removal(&self, path: &Path, report: &Report) -> Result<Option<Action>> {
+        if report.restored.contains(path) {
+            return Ok(Some(Action::Restore));
+        }
+        if std::fs::symlink_metadata(path).is_err() {
+            return Ok(None);
+        }
+        Ok(Some(match self.overwrite {
+            Overwrite::Always => Action::Replace,
+            Overwrite::Never | Overwrite::IfNewer => Action::Skip,
+            Overwrite::Backup => Action::Preserve(backup_name(path)),
+            Overwrite::Ask => Action::Ask,
+        }))
+    }
+
+    /// Carry out a decision about `path`, asking `question` about it if needed, and return
+    /// whether to go ahead with it.
+    fn apply(
+        &self,
+        path: &Path,
+        action: Action,
+        question: &str,
+        report: &mut Report,
+    ) -> Result<bool> {
+        match action {
+            Action::Restore | Action::Merge => Ok(true),
+            Action::Replace => {
+                report.replaced.push(path.to_path_buf());
+                Ok(true)
+            }
+            Action::Skip => {
+                report.skipped.push(path.to_path_buf());
+                Ok(false)
+            }
+            Action::Preserve(backup) => {
+                debug!("Moving {:?} to {:?}", path, backup);
+                std::fs::rename(path, &backup)?;
+                report.preserved.push((path.to_path_buf(), backup));
+                Ok(true)
+            }
+            Action::Ask => {
+                let action = if confirm(&format!("{} {}?", question, path.display()))? {
+                    Action::Replace
+                } else {
+                    Action::Skip
+                };
+                self.apply(path, action, question, report)
+            }
+        }
+    }
+
+    /// Decide whether an entry should be restored to `path`, making way for it if it should.
+    pub(crate) fn prepare(
+        &self,
+        path: &Path,
+        is_dir: bool,
+        mtime: SystemTime,
+        report: &mut Report,
+    ) -> Result<bool> {
+        let action = self.decide(path, is_dir, mtime, report)?;
+        let restore = self.apply(path, action, "Overwrite", report)?;
+        if restore {
+            report.restored.insert(path.to_path_buf());
+        }
+        Ok(restore)
+    }
+
+    /// Decide whether a file that a backup recorded as deleted should be removed from `path`,
+    /// moving it out of the way instead if the policy says to.
+    pub(crate) fn prepare_removal(&self, path: &Path, report: &mut Report) -> Result<bool> {
+        let Some(action) = self.decide_removal(path, report)? else {
+            return Ok(false);
+        };
+        let preserve = matches!(action, Action::Preserve(_));
+        let remove = self.apply(path, action, "Remove", report)?;
+        report.restored.remove(path);
+        Ok(remove && !preserve)
+    }
+
+    /// Test whether the directory `path` is restored into stays inside `root`, the canonical form
+    /// of the target's root, without creating anything. A symlink restored earlier must not lead
+    /// the entries after it out of the root, so any symlink on the way must resolve inside it.
+    pub(crate) fn contains(&self, path: &Path, root: &Path) -> Result<bool> {
+        let Ok(relative) = path.strip_prefix(&self.root) else {
+            return Ok(false);
+        };
+        let mut dir = self.root.clone();
+        for component in relative.parent().into_iter().flat_map(Path::components) {
+            dir.push(component);
+            match std::fs::symlink_metadata(&dir) {
+                Ok(existing) if existing.is_symlink() => match std::fs::canonicalize(&dir) {
+                    Ok(resolved) if resolved.starts_with(root) => {}
+                    _ => return Ok(false),
+                },
+                Ok(_) => {}
+                // Nothing below here exists yet, so there are no more symlinks to follow
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(true),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(true)
+    }
+
+    /// The path that the entry named `name` is restored to, or None if it should not be.
+    pub fn path_of(&self, name: &Path) -> Option<PathBuf> {
+        let name = archive_name(name);
+        // Never let an entry escape the root
+        if name
+            .components()
+            .any(|component| component == Component::ParentDir)
+        {
+            return None;
+        }
+        match &self.strip_prefix {
+            Some(prefix) => Some(
+                self.root
+                    .join(name.strip_prefix(archive_name(prefix)).ok()?),
+            ),
+            None => Some(self.root.join(name)),
+        }
+    }
+}
+
+/// How much has been written to an archive so far.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Progress {
+    pub entries: u64,
+    pub bytes: u64,
+}
+
+/// How often `ArchiveWriter` reports its progress by default: after every this many entries or
+/// bytes, whichever comes first.
+pub const REPORT_EVERY: Progress = Progress {
+    entries: 10_000,
+    bytes: 1 << 30,
+};
+
+/// A gzipped tarball that is written as it is built.
+pub struct ArchiveWriter<W: Write> {
+    builder: tar::Builder<GzEncoder<W>>,
+    progress: Progress,
+    reported: Progress,
+    every: Progress,
+    on_progress: Box<dyn FnMut(&Progress)>,
+}
+
+impl<W: Write> ArchiveWriter<W> {
+    /// Create a writer that logs its progress every `REPORT_EVERY`.
+    pub fn new(writer: W) -> ArchiveWriter<W> {
+        ArchiveWriter::with_progress(writer, REPORT_EVERY, |progress| {
+            info!(
+                "Archived {} entries ({} bytes) so far",
+                progress.entries, progress.bytes
+            )
+        })
+    }
+
+    /// Create a writer that calls `on_progress` each time another `every` entries or bytes have
+    /// been archived.
+    pub fn with_progress(
+        writer: W,
+        every: Progress,
+        on_progress: impl FnMut(&Progress) + 'static,
+    ) -> ArchiveWriter<W> {
+        let mut builder = tar::Builder::new(GzEncoder::new(writer, Compression::default()));
+        builder.follow_symlinks(false);
+        ArchiveWriter {
+            builder,
+            progress: Progress::default(),
+            reported: Progress::default(),
+            every,
+            on_progress: Box::new(on_progress),
+        }
+    }
+
+    /// Add a single file, directory or symlink to the archive. Directory contents are not added.
+    pub fn append(&mut self, path: &Path) -> Result<()> {
+        debug!("Archiving {:?}", path);
+        let metadata = std::fs::symlink_metadata(path)?;
+        self.builder
+            .append_path_with_name(path, archive_name(path))?;
+        self.progress.entries += 1;
+        if metadata.is_file() {
+            self.progress.bytes += metadata.len();
+        }
+        if self.progress.entries - self.reported.entries >= self.every.entries
+            || self.progress.bytes - self.reported.bytes >= self.every.bytes
+        {
+            (self.on_progress)(&self.progress);
+            self.reported = self.progress.clone();
+        }
+        Ok(())
+    }
+
+    pub fn progress(&self) -> &Progress {
+        &self.progress
+    }
+
+    /// Write the end of the archive and flush the compressor, returning the underlying writer.
+    pub fn finish(self) -> Result<W> {
+        info!(
+            "Archived {} entries ({} bytes)",
+            self.progress.entries, self.progress.bytes
+        );
+        let encoder = self.builder.into_inner()?;
+        Ok(encoder.finish()?)
+    }
+}
+
+/// List the given paths and everything beneath them, with directories before their contents.
+pub fn walk(paths: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    fn visit(path: &Path, found: &mut Vec<PathBuf>) -> Result<()> {
+        found.push(path.to_path_buf());
+        if std::fs::symlink_metadata(path)?.is_dir() {
+            let mut children: Vec<PathBuf> = std::fs::read_dir(path)?
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<std::io::Result<_>>()?;
+            children.sort();
+            for child in children.iter() {
+                visit(child, found)?;
+            }
+        }
+        Ok(())
+    }
+    let mut found = vec![];
+    for path in paths.iter() {
+        visit(path, &mut found)?;
+    }
+    Ok(found)
+}
+
+/// Write a gzipped tarball of the given paths, recursing into directories.
+pub fn write_archive<W: Write>(paths: &[PathBuf], writer: W) -> Result<W> {
+    write_entries(&walk(paths)?, writer)
+}
+
+/// Write a gzipped tarball containing exactly the given paths, without recursing.
+pub fn write_entries<W: Write>(paths: &[PathBuf], writer: W) -> Result<W> {
+    let mut archive = ArchiveWriter::new(writer);
+    for path in paths.iter() {
+        archive.append(path)?;
+    }
+    archive.finish()
+}
+
+/// Test if an archive entry was requested. An empty list of globs requests everything, and a
+/// directory requests everything inside it.
+pub(crate) fn is_requested(name: &Path, globs: &[glob::Pattern]) -> bool {
+    globs.is_empty()
+        || name.ancestors().any(|ancestor| {
+            globs
+                .iter()
+                .any(|g| !ancestor.as_os_str().is_empty() && g.matches_path(ancestor))
+        })
+}
+
+/// Compile globs for matching against archive entries. Leading `/`s are ignored, so both
+/// `/home/me/file` and `home/me/file` match the same entry.
+pub(crate) fn compile_globs(globs: &[String]) -> Result<Vec<glob::Pattern>> {
+    Ok(globs
+        .iter()
+        .map(|g| glob::Pattern::new(g.trim_start_matches('/')))
+        .collect::<std::result::Result<_, _>>()?)
+}
+
+/// Extract a gzipped tarball to `target`, optionally only extracting entries matching `globs`.
+///
+/// Entry names are always treated as relative to the target's root, even if they were archived
+/// with an absolute path. Globs match the names in the archive, before any prefix is stripped.
+pub fn extract_archive<R: Read>(
+    reader: R,
+    globs: &[String],
+    target: &Target,
+    report: &mut Report,
+) -> Result<Progress> {
+    let patterns = compile_globs(globs)?;
+    std::fs::create_dir_all(&target.root)?;
+    let root = std::fs::canonicalize(&target.root)?;
+    let mut archive = tar::Archive::new(GzDecoder::new(reader));
+    archive.set_preserve_permissions(true);
+    archive.set_preserve_mtime(true);
+    archive.set_overwrite(true);
+    let mut progress = Progress::default();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let name = archive_name(&entry.path()?);
+        if !is_requested(&name, &patterns) {
+            continue;
+        }
+        let Some(path) = target.path_of(&name) else {
+            if target.strip_prefix.is_none() {
+                warn!("Skipped suspicious entry {:?}", name);
+            }
+            continue;
+        };
+        if !target.contains(&path, &root)? {
+            warn!("Skipped suspicious entry {:?}", name);
+            continue;
+        }
+        let header = entry.header();
+        let entry_type = header.entry_type();
+        // Hard links are made to an entry restored earlier, which must be inside the root too
+        let link_source = if entry_type.is_hard_link() {
+            match entry
+                .link_name()?
+                .and_then(|source| target.path_of(&source))
+            {
+                Some(source) if target.contains(&source, &root)? => Some(source),
+                _ => {
+                    warn!("Skipped suspicious entry {:?}", name);
+                    continue;
+                }
+            }
+        } else {
+            None
+        };
+        let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(header.mtime()?);
+        if !target.prepare(&path, entry_type.is_dir(), mtime, report)? {
+            continue;
+        }
+        debug!("Extracting {:?} to {:?}", name, path);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        // Replace a symlink rather than writing through it, and a file rather than linking to it
+        match std::fs::symlink_metadata(&path) {
+            Ok(existing)
+                if existing.is_symlink() || (link_source.is_some() && !existing.is_dir()) =>
+            {
+                std::fs::remove_file(&path)?
+            }
+            _ => {}
+        }
+        match link_source {
+            Some(source) => std::fs::hard_link(source, &path)?,
+            None => {
+                entry.unpack(&path)?;
+            }
+        }
+        progress.entries += 1;
+        progress.bytes += entry.size();
+    }
+    info!(
+        "Extracted {} entries ({} bytes)",
+        progress.entries, progress.bytes
+    );
+    Ok(progress)
+}
+
+/// A single entry in a backup, as listed without extracting it.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct EntryInfo {
+    /// The name the entry is stored under, without the leading `/`.
+    pub path: PathBuf,
+    pub kind: Kind,
+    pub size: u64,
+    pub mode: u32,
+    pub mtime: SystemTime,
+    /// The target of a symlink.
+    pub target: Option<PathBuf>,
+}
+
+/// List the entries of an archive, optionally only those matching `globs`.
+pub fn list_archive<R: Read>(reader: R, globs: &[String]) -> Result<Vec<EntryInfo>> {
+    let patterns = compile_globs(globs)?;
+    let mut archive = tar::Archive::new(GzDecoder::new(reader));
+    let mut entries = vec![];
+    for entry in archive.entries()? {
+        let entry = entry?;
+        let path = archive_name(&entry.path()?);
+        if !is_requested(&path, &patterns) {
+            continue;
+        }
+        let header = entry.header();
+        let entry_type = header.entry_type();
+        let kind = if entry_type.is_file() {
+            Kind::File
+        } else if entry_type.is_dir() {
+            Kind::Dir
+        } else if entry_type.is_symlink() {
+            Kind::Symlink
+        } else {
+            Kind::Other
+        };
+        entries.push(EntryInfo {
+            path,
+            kind,
+            size: entry.size(),
+            mode: header.mode()?,
+            mtime: SystemTime::UNIX_EPOCH + Duration::from_secs(header.mtime()?),
+            target: entry.link_name()?.map(|target| target.to_path_buf()),
+        });
+    }
+    Ok(entries)
+}
+
+/// Read an archive all the way through, returning the SHA-256 of every file in it. Any damage
+/// to the archive, including to the gzip trailer, is returned as an error.
+pub fn hash_archive<R: Read>(reader: R) -> Result<Vec<(PathBuf, String)>> {
+    let mut archive = tar::Archive::new(GzDecoder::new(reader));
+    let mut hashes = vec![];
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = archive_name(&entry.path()?);
+        let mut hasher = Sha256::new();
+        std::io::copy(&mut entry, &mut hasher)?;
+        if entry.header().entry_type().is_file() {
+            hashes.push((path, format!("{:x}", hasher.finalize())));
+        }
+    }
+    // tar stops at the end of the archive, but gzip only checks its trailer at the end of stream
+    std::io::copy(&mut archive.into_inner(), &mut std::io::sink())?;
+    Ok(hashes)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Create a fresh directory to run a test in.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("backer-upper-archive-{}", name));
+        if dir.exists() {
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+        std::fs::create_dir_all(dir.join("src/dir")).unwrap();
+        std::fs::write(dir.join("src/a.txt"), "a").unwrap();
+        std::fs::write(dir.join("src/dir/b.txt"), "b").unwrap();
+        dir
+    }
+
+    fn entry_names(archive: &[u8]) -> Vec<PathBuf> {
+        tar::Archive::new(GzDecoder::new(archive))
+            .entries()
+            .unwrap()
+            .map(|entry| entry.unwrap().path().unwrap().to_path_buf())
+            .collect()
+    }
+
+    #[test]
+    fn test_archive_name() {
+        assert_eq!(archive_name(Path::new("/a/b")), PathBuf::from("a/b"));
+        assert_eq!(archive_name(Path::new("a/b")), PathBuf::from("a/b"));
+    }
+
+    #[test]
+    fn test_write_archive_recurses() {
+        let dir = test_dir("recurses");
+        let archive = write_archive(&[dir.join("src")], vec![]).unwrap();
+        let src = archive_name(&dir.join("src"));
+        assert_eq!(
+            entry_names(&archive),
+            vec![
+                src.clone(),
+                src.join("a.txt"),
+                src.join("dir"),
+                src.join("dir/b.txt")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_archive_writer_reports_progress() {
+        let dir = test_dir("progress");
+        let reports = std::rc::Rc::new(std::cell::RefCell::new(vec![]));
+        let every = Progress {
+            entries: 2,
+            bytes: u64::MAX,
+        };
+        let sink = reports.clone();
+        let mut archive = ArchiveWriter::with_progress(vec![], every, move |progress| {
+            sink.borrow_mut().push(progress.entries)
+        });
+        for path in walk(&[dir.join("src")]).unwrap().iter() {
+            archive.append(path).unwrap();
+        }
+        archive.finish().unwrap();
+        assert_eq!(*reports.borrow(), vec![2, 4]);
+    }
+
+    #[test]
+    fn test_system_tar_extracts_archive() {
+        let dir = test_dir("system-tar");
+        let archive = write_archive(&[dir.join("src")], vec![]).unwrap();
+        std::fs::write(dir.join("backup.tar.gz"), archive).unwrap();
+        let root = dir.join("root");
+        std::fs::create_dir(&root).unwrap();
+        let status = std::process::Command::new("tar")
+            .arg("-xzf")
+            .arg(dir.join("backup.tar.gz"))
+            .arg("-C")
+            .arg(&root)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let restored = root.join(archive_name(&dir));
+        assert_eq!(
+            std::fs::read_to_string(restored.join("src/a.txt")).unwrap(),
+            "a"
+        );
+        assert_eq!(
+            std::fs::read_to_string(restored.join("src/dir/b.txt")).unwrap(),
+            "b"
+        );
+    }
+
+    #[test]
+    fn test_extract_archive() {
+        let dir = test_dir("extract");
+        let archive = write_archive(&[dir.join("src")], vec![]).unwrap();
+        let root = dir.join("root");
+        let progress = extract_archive(
+            archive.as_slice(),
+            &[],
+            &Target::new(&root),
+            &mut Report::default(),
+        )
+        .unwrap();
+        assert_eq!(progress.entries, 4);
+        assert_eq!(progress.bytes, 2);
+        let restored = root.join(archive_name(&dir));
+        assert_eq!(
+            std::fs::read_to_string(restored.join("src/dir/b.txt")).unwrap(),
+            "b"
+        );
+    }
+
+    #[test]
+    fn test_extract_archive_globs() {
+        let dir = test_dir("globs");
+        let archive = write_archive(&[dir.join("src")], vec![]).unwrap();
+        let root = dir.join("root");
+        let glob = format!("{}/*/dir", dir.display());
+        extract_archive(
+            archive.as_slice(),
+            &[glob],
+            &Target::new(&root),
+            &mut Report::default(),
+        )
+        .unwrap();
+        let restored = root.join(archive_name(&dir));
+        assert!(restored.join("src/dir/b.txt").exists());
+        assert!(!restored.join("src/a.txt").exists());
+    }
+
+    #[test]
+    fn test_target_path_of() {
+        let target = Target::new(Path::new("/restore"));
+        assert_eq!(
+            target.path_of(Path::new("/home/me/a.txt")),
+            Some(PathBuf::from("/restore/home/me/a.txt"))
+        );
+        assert_eq!(target.path_of(Path::new("home/../../etc/passwd")), None);
+        let target = Target {
+            strip_prefix: Some(PathBuf::from("/home/me")),
+            ..target
+        };
+        assert_eq!(
+            target.path_of(Path::new("home/me/a.txt")),
+            Some(PathBuf::from("/restore/a.txt"))
+        );
+        assert_eq!(target.path_of(Path::new("home/you/a.txt")), None);
+    }
+
+    #[test]
+    fn test_extract_archive_strip_prefix() {
+        let dir = test_dir("strip-prefix");
+        let archive = write_archive(&[dir.join("src")], vec![]).unwrap();
+        let root = dir.join("root");
+        let target = Target {
+            strip_prefix: Some(dir.join("src")),
+            ..Target::new(&root)
+        };
+        extract_archive(archive.as_slice(), &[], &target, &mut Report::default()).unwrap();
+        assert_eq!(std::fs::read_to_string(root.join("a.txt")).unwrap(), "a");
+        assert_eq!(
+            std::fs::read_to_string(root.join("dir/b.txt")).unwrap(),
+            "b"
+        );
+    }
+
+    /// Build an archive by hand, with a symlink, hard link or file for each name.
+    fn evil_archive(entries: &[(&str, tar::EntryType, &Path)]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(GzEncoder::new(vec![], Compression::default()));
+        for (name, entry_type, link) in entries.iter() {
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(*entry_type);
+            header.set_mode(0o644);
+            header.set_size(0);
+            if *entry_type == tar::EntryType::Regular {
+                builder.append_data(&mut header, name, &[][..]).unwrap();
+            } else {
+                builder.append_link(&mut header, name, link).unwrap();
+            }
+        }
+        builder.into_inner().unwrap().finish().unwrap()
+    }
+
+    #[test]
+    fn test_extract_archive_stays_in_root() {
+        let dir = test_dir("escape");
+        let outside = dir.join("outside");
+        std::fs::create_dir_all(&outside).unwrap();
+        std::fs::write(outside.join("victim"), "original").unwrap();
+        let archive = evil_archive(&[
+            ("link", tar::EntryType::Symlink, &outside),
+            (
+                "link/newdir/deeper/passwd",
+                tar::EntryType::Regular,
+                Path::new(""),
+            ),
+            ("hard", tar::EntryType::Link, Path::new("link/victim")),
+            (
+                "harder",
+                tar::EntryType::Link,
+                Path::new("../outside/victim"),
+            ),
+            ("file", tar::EntryType::Regular, Path::new("")),
+            ("linked", tar::EntryType::Link, Path::new("file")),
+        ]);
+        let root = dir.join("root");
+        extract_archive(
+            archive.as_slice(),
+            &[],
+            &Target::new(&root),
+            &mut Report::default(),
+        )
+        .unwrap();
+        assert!(root.join("link").is_symlink());
+        assert!(!outside.join("newdir").exists());
+        assert!(!root.join("hard").exists());
+        assert!(!root.join("harder").exists());
+        assert!(root.join("linked").is_file());
+        assert_eq!(
+            std::fs::read_dir(&outside).unwrap().count(),
+            1,
+            "nothing is created outside of the target"
+        );
+    }
+
+    #[test]
+    fn test_hash_archive() {
+        let dir = test_dir("hash");
+        let archive = write_archive(&[dir.join("src")], vec![]).unwrap();
+        let hashes = hash_archive(archive.as_slice()).unwrap();
+        assert_eq!(hashes.len(), 2);
+        assert_eq!(
+            hashes[0],
+            (
+                archive_name(&dir.join("src/a.txt")),
+                // echo -n a | sha256sum
+                "ca978112ca1bbdcafac231b39a23dc4da786eff8147c4e72b9807785afee48bb".to_string()
+            )
+        );
+        // Cutting off the gzip trailer is noticed
+        assert!(hash_archive(&archive[..archive.len() - 4]).is_err());
+    }
+}
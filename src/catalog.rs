@@ -0,0 +1,215 @@
+//! A record of every backup in a directory, so that scheduling and pruning do not depend on
+//! file names and modification times.
+
+use std::collections::{BTreeSet, HashSet};
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::error::Result;
+use crate::stream::{write_to, Destination, PARTIAL_SUFFIX};
+
+/// The name of the catalog file in a backup directory.
+pub const CATALOG_NAME: &str = "catalog.json";
+
+/// A single backup, as recorded when it was taken.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CatalogEntry {
+    pub name: String,
+    pub created: DateTime<Utc>,
+    /// The name of the config that took the backup.
+    pub config: String,
+    pub globs: Vec<String>,
+    /// The size of the backup as stored. Unknown for backups taken before there was a catalog.
+    pub size: Option<u64>,
+    /// The SHA-256 of the backup as stored. Unknown for backups taken before there was a catalog.
+    pub sha256: Option<String>,
+    /// Who the backup was encrypted for, if anyone.
+    pub gpg_id: Option<String>,
+    /// The backup this one is an increment on, or None for a full backup.
+    pub parent: Option<String>,
+}
+
+/// Every backup in a directory, from every config that shares it.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Catalog {
+    pub backups: Vec<CatalogEntry>,
+    /// The configs whose backups from before there was a catalog have already been looked for.
+    #[serde(default)]
+    pub imported: BTreeSet<String>,
+}
+
+impl Catalog {
+    /// Where the catalog of a backup directory is stored.
+    pub fn destination(host: &Option<String>, dir: &Path) -> Destination {
+        Destination::new(host, dir.join(CATALOG_NAME))
+    }
+
+    /// Load a catalog, or start an empty one if there is none yet.
+    pub fn load(catalog: &Destination) -> Result<Catalog> {
+        if !catalog.exists()? {
+            return Ok(Catalog::default());
+        }
+        let mut source = catalog.open(&None)?;
+        let loaded = serde_json::from_reader(&mut source);
+        source.finish()?;
+        Ok(loaded?)
+    }
+
+    /// Save a catalog, replacing the old one only once the new one has been written completely.
+    pub fn save(&self, catalog: &Destination) -> Result<()> {
+        let partial = catalog.sidecar(PARTIAL_SUFFIX);
+        write_to(&partial, &None, |sink| {
+            Ok(serde_json::to_writer_pretty(sink, self)?)
+        })?;
+        partial.rename(catalog.path())
+    }
+
+    /// The backups taken by a config, newest first.
+    pub fn backups(&self, config: &str) -> Vec<&CatalogEntry> {
+        let mut backups: Vec<&CatalogEntry> = self
+            .backups
+            .iter()
+            .filter(|backup| backup.config == config)
+            .collect();
+        backups.sort_by_key(|backup| std::cmp::Reverse(backup.created));
+        backups
+    }
+
+    pub fn add(&mut self, entry: CatalogEntry) {
+        self.backups.push(entry);
+    }
+
+    pub fn remove(&mut self, names: &[String]) {
+        self.backups.retain(|backup| !names.contains(&backup.name));
+    }
+
+    /// The names of a config's backups that can be removed to leave only the newest `copies`.
+    pub fn redundant(&self, config: &str, copies: usize) -> Vec<String> {
+        let keep: HashSet<String> = self
+            .backups(config)
+            .iter()
+            .take(copies)
+            .map(|backup| backup.name.clone())
+            .collect();
+        self.redundant_except(config, &keep)
+    }
+
+    /// The names of a config's backups that can be removed to leave only those in `keep`.
+    /// Incremental backups are useless without the backups they are based on, so those are
+    /// never redundant.
+    pub fn redundant_except(&self, config: &str, keep: &HashSet<String>) -> Vec<String> {
+        let keep = self.with_parents(config, keep);
+        self.backups(config)
+            .iter()
+            .filter(|backup| !keep.contains(&backup.name))
+            .map(|backup| backup.name.clone())
+            .collect()
+    }
+
+    /// Some of a config's backups, along with every backup they are increments on.
+    pub fn with_parents(&self, config: &str, names: &HashSet<String>) -> HashSet<String> {
+        let backups = self.backups(config);
+        let mut names = names.clone();
+        let mut pending: Vec<String> = names.iter().cloned().collect();
+        while let Some(name) = pending.pop() {
+            let parent = backups
+                .iter()
+                .find(|backup| backup.name == name)
+                .and_then(|backup| backup.parent.as_ref());
+            if let Some(parent) = parent {
+                if names.insert(parent.clone()) {
+                    pending.push(parent.clone());
+                }
+            }
+        }
+        names
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn entry(name: &str, day: u32, parent: Option<&str>) -> CatalogEntry {
+        CatalogEntry {
+            name: name.to_string(),
+            created: Utc.with_ymd_and_hms(2000, 1, day, 0, 0, 0).unwrap(),
+            config: "config".to_string(),
+            globs: vec![],
+            size: None,
+            sha256: None,
+            gpg_id: None,
+            parent: parent.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn test_backups_newest_first() {
+        let mut catalog = Catalog::default();
+        catalog.add(entry("a", 1, None));
+        catalog.add(entry("c", 3, None));
+        catalog.add(entry("b", 2, None));
+        catalog.add(CatalogEntry {
+            config: "other".to_string(),
+            ..entry("d", 4, None)
+        });
+        let names: Vec<&str> = catalog
+            .backups("config")
+            .iter()
+            .map(|backup| backup.name.as_str())
+            .collect();
+        assert_eq!(names, vec!["c", "b", "a"]);
+    }
+
+    #[test]
+    fn test_redundant() {
+        let mut catalog = Catalog::default();
+        catalog.add(entry("a", 1, None));
+        catalog.add(entry("b", 2, None));
+        catalog.add(entry("c", 3, None));
+        assert_eq!(catalog.redundant("config", 2), vec!["a"]);
+        assert_eq!(catalog.redundant("config", 3), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_redundant_keeps_chains() {
+        let mut catalog = Catalog::default();
+        catalog.add(entry("a", 1, None));
+        catalog.add(entry("b", 2, None));
+        catalog.add(entry("c", 3, Some("b")));
+        catalog.add(entry("d", 4, Some("c")));
+        assert_eq!(catalog.redundant("config", 1), vec!["a"]);
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let dir = std::env::temp_dir().join("backer-upper-catalog");
+        std::fs::create_dir_all(&dir).unwrap();
+        let destination = Catalog::destination(&None, &dir);
+        let _ = std::fs::remove_file(destination.path());
+        assert_eq!(Catalog::load(&destination).unwrap(), Catalog::default());
+        let mut catalog = Catalog::default();
+        catalog.add(entry("a", 1, None));
+        catalog.save(&destination).unwrap();
+        assert_eq!(Catalog::load(&destination).unwrap(), catalog);
+        assert!(!dir.join("catalog.json.partial").exists());
+    }
+
+    #[test]
+    fn test_load_without_imported() {
+        let dir = std::env::temp_dir().join("backer-upper-catalog-imported");
+        std::fs::create_dir_all(&dir).unwrap();
+        let destination = Catalog::destination(&None, &dir);
+        // Catalogs written before imports were recorded
+        std::fs::write(destination.path(), r#"{"backups": []}"#).unwrap();
+        let mut catalog = Catalog::load(&destination).unwrap();
+        assert!(catalog.imported.is_empty());
+        catalog.imported.insert("config".to_string());
+        catalog.save(&destination).unwrap();
+        assert_eq!(Catalog::load(&destination).unwrap(), catalog);
+    }
+}
@@ -0,0 +1,84 @@
+//! SHA-256 sidecars, written next to backups in the format that `sha256sum -c` reads.
+
+use std::io::Read;
+
+use crate::error::{Error, Result};
+use crate::stream::{store, Destination, Written};
+
+/// Added to the name of a backup to get the name of its checksum.
+pub const SHA256_SUFFIX: &str = ".sha256";
+
+/// Record the SHA-256 of a backup next to it. Like the backup, it is written under a temporary
+/// name first, so an interrupted sync never leaves a truncated checksum behind.
+pub fn write_checksum(backup: &Destination, written: &Written) -> Result<()> {
+    // A format such as `backups/..` names a directory rather than a file
+    let name = backup
+        .path()
+        .file_name()
+        .ok_or_else(|| {
+            Error::InvalidConfig(format!(
+                "the backup {:?} doesn't have a file name",
+                backup.path()
+            ))
+        })?
+        .to_string_lossy()
+        .into_owned();
+    store(&backup.sidecar(SHA256_SUFFIX), &None, |sink| {
+        Ok(writeln!(sink, "{}  {}", written.sha256, name)?)
+    })?;
+    Ok(())
+}
+
+/// Load the SHA-256 recorded next to a backup, if there is one.
+pub fn read_checksum(backup: &Destination) -> Result<Option<String>> {
+    let sidecar = backup.sidecar(SHA256_SUFFIX);
+    if !sidecar.exists()? {
+        return Ok(None);
+    }
+    let mut source = sidecar.open(&None)?;
+    let mut contents = String::new();
+    let read = source.read_to_string(&mut contents);
+    source.finish()?;
+    read?;
+    match contents.split_whitespace().next() {
+        Some(sha256) => Ok(Some(sha256.to_string())),
+        None => Err(Error::Corrupt(
+            sidecar.path().to_path_buf(),
+            "the checksum is empty".to_string(),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::stream::write_to;
+
+    #[test]
+    fn test_write_and_read_checksum() {
+        let path = std::env::temp_dir().join("backer-upper-checksum.tar.gz");
+        let backup = Destination::Local(path.clone());
+        let written = write_to(&backup, &None, |sink| Ok(sink.write_all(b"a")?)).unwrap();
+        write_checksum(&backup, &written).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(backup.sidecar(SHA256_SUFFIX).path()).unwrap(),
+            "ca978112ca1bbdcafac231b39a23dc4da786eff8147c4e72b9807785afee48bb  \
+             backer-upper-checksum.tar.gz\n"
+        );
+        assert_eq!(backup.measure().unwrap(), written);
+        assert_eq!(read_checksum(&backup).unwrap(), Some(written.sha256));
+    }
+
+    #[test]
+    fn test_write_checksum_without_file_name() {
+        let written = Written {
+            size: 0,
+            sha256: String::new(),
+        };
+        let backup = Destination::Local(std::env::temp_dir().join(".."));
+        assert!(matches!(
+            write_checksum(&backup, &written),
+            Err(Error::InvalidConfig(_))
+        ));
+    }
+}
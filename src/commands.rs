@@ -1,20 +1,57 @@
 use clap::{Parser, Subcommand};
 use std::path::PathBuf;
 
+use crate::archive::{Overwrite, Target};
+use crate::error::{Error, Result};
+use crate::health::State;
+
 pub mod backup;
+pub mod contents;
+pub mod daemon;
+pub mod list;
+pub mod prune;
 pub mod restore;
+pub mod status;
 pub mod sync;
+pub mod verify;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 pub struct Cli {
     #[command(subcommand)]
     pub commands: Commands,
+    /// Print what would be done without doing any of it.
+    #[arg(long, global = true)]
+    pub dry_run: bool,
 }
 
 impl Cli {
-    pub fn run_command(&self) -> Result<(), clap::error::Error> {
+    /// The exit code to use when a command fails with `err`. `status` is a Nagios check, so it
+    /// exits with UNKNOWN for anything that isn't the state of the backups.
+    pub fn exit_code(&self, err: &Error) -> i32 {
+        match (&self.commands, err) {
+            (Commands::Status { .. }, Error::Status(state)) => state.exit_code(),
+            (Commands::Status { .. }, _) => State::Unknown.exit_code(),
+            _ => err.exit_code(),
+        }
+    }
+
+    /// Test if the command line runs `status`, for when it can't be parsed. The only option
+    /// before the subcommand is a flag, so the subcommand is the first argument that isn't one.
+    pub fn is_status(args: impl IntoIterator<Item = String>) -> bool {
+        args.into_iter()
+            .skip(1)
+            .find(|arg| !arg.starts_with('-'))
+            .is_some_and(|arg| arg == "status")
+    }
+
+    pub fn run_command(&self) -> Result<()> {
         match &self.commands {
+            Commands::Backup {
+                globs,
+                output,
+                gpg_id,
+            } if self.dry_run => backup::plan_backup(globs, output, gpg_id),
             Commands::Backup {
                 globs,
                 output,
@@ -24,8 +61,61 @@ impl Cli {
                 file,
                 globs,
                 gpg_id,
-            } => restore::restore(file, globs, gpg_id),
-            Commands::Sync { file } => sync::sync(file),
+                target,
+                strip_prefix,
+                no_clobber,
+                overwrite_if_newer,
+                backup_existing,
+                interactive,
+            } => {
+                let overwrite = if *no_clobber {
+                    Overwrite::Never
+                } else if *overwrite_if_newer {
+                    Overwrite::IfNewer
+                } else if *backup_existing {
+                    Overwrite::Backup
+                } else if *interactive {
+                    Overwrite::Ask
+                } else {
+                    Overwrite::Always
+                };
+                let target = Target {
+                    root: target.clone(),
+                    strip_prefix: strip_prefix.clone(),
+                    overwrite,
+                };
+                if self.dry_run {
+                    let plan = restore::plan_restore(file, globs, gpg_id, &target)?;
+                    restore::print_plan(&plan);
+                    return Ok(());
+                }
+                let report = restore::restore_to(file, globs, gpg_id, &target)?;
+                restore::print_report(&report);
+                Ok(())
+            }
+            Commands::Contents {
+                file,
+                globs,
+                gpg_id,
+            } => contents::contents(file, globs, gpg_id),
+            Commands::Sync { file } => sync::sync(file, self.dry_run),
+            Commands::Daemon { file } => daemon::daemon(file, self.dry_run),
+            Commands::Verify {
+                file,
+                section,
+                config: true,
+                ..
+            } => verify::verify_file(file, section),
+            Commands::Verify { file, gpg_id, .. } => verify::verify(file, gpg_id),
+            Commands::List {
+                file,
+                section,
+                json,
+            } => list::list(file, section, *json),
+            Commands::Status { file, section } => status::status(file, section),
+            Commands::Prune { file, section, yes } => {
+                prune::prune(file, section, *yes && !self.dry_run)
+            }
         }
     }
 }
@@ -52,6 +142,36 @@ pub enum Commands {
         /// Optional. The id of the GPG key used to encrypt the archive.
         #[arg(short, long)]
         gpg_id: Option<String>,
+        /// Restore files under this directory instead of to where they were backed up from.
+        #[arg(short, long, default_value = "/")]
+        target: PathBuf,
+        /// Optional. Remove this prefix from the paths of restored files. Files outside of it
+        /// are not restored.
+        #[arg(long)]
+        strip_prefix: Option<PathBuf>,
+        /// Never overwrite existing files.
+        #[arg(short, long, group = "overwrite")]
+        no_clobber: bool,
+        /// Only overwrite existing files that are older than the ones in the backup.
+        #[arg(long, group = "overwrite")]
+        overwrite_if_newer: bool,
+        /// Rename existing files to make way, adding `.orig` to their names.
+        #[arg(short, long, group = "overwrite")]
+        backup_existing: bool,
+        /// Ask before overwriting or removing each existing file.
+        #[arg(short, long, group = "overwrite")]
+        interactive: bool,
+    },
+    /// List the files in a backup without restoring them.
+    #[command(alias = "ls")]
+    Contents {
+        /// The backup to list.
+        file: PathBuf,
+        /// Optional. Only list files matching these globs.
+        globs: Option<Vec<String>>,
+        /// Optional. The id of the GPG key used to encrypt the backup.
+        #[arg(short, long)]
+        gpg_id: Option<String>,
     },
     /// Synchronize any number of backups according to a schedule.
     ///
@@ -60,4 +180,79 @@ pub enum Commands {
         /// The TOML file describing the backups.
         file: PathBuf,
     },
+    /// Keep synchronizing backups, sleeping until the next one is due.
+    ///
+    /// Send SIGHUP to read the file again, and SIGTERM to stop once any backup in progress is
+    /// finished.
+    Daemon {
+        /// The TOML file describing the backups.
+        file: PathBuf,
+    },
+    /// Check that backups are intact and can be restored.
+    Verify {
+        /// The backup to verify, or with --config, the TOML file describing the backups.
+        file: PathBuf,
+        /// Optional. With --config, only verify the backups of this section.
+        #[arg(requires = "config")]
+        section: Option<String>,
+        /// Verify every backup listed in a sync file instead of a single backup.
+        #[arg(short, long)]
+        config: bool,
+        /// Optional. The id of the GPG key used to encrypt the backup.
+        #[arg(short, long, conflicts_with = "config")]
+        gpg_id: Option<String>,
+    },
+    /// List the backups taken by a sync file.
+    List {
+        /// The TOML file describing the backups.
+        file: PathBuf,
+        /// Optional. Only list the backups of this section.
+        section: Option<String>,
+        /// Print the backups as JSON.
+        #[arg(long)]
+        json: bool,
+    },
+    /// Show how healthy the backups of a sync file are.
+    ///
+    /// Exits with 0 if every section is OK, 1 for WARN, 2 for CRITICAL and 3 for UNKNOWN, like a
+    /// Nagios check. Any other error, including invalid arguments, is UNKNOWN too.
+    Status {
+        /// The TOML file describing the backups.
+        file: PathBuf,
+        /// Optional. Only show the backups of this section.
+        section: Option<String>,
+    },
+    /// Remove the backups a sync file's retention rules no longer keep, without taking new ones.
+    ///
+    /// Without --yes, only shows which backups would be kept and removed, and why.
+    Prune {
+        /// The TOML file describing the backups.
+        file: PathBuf,
+        /// Optional. Only prune the backups of this section.
+        section: Option<String>,
+        /// Actually remove the backups.
+        #[arg(short, long)]
+        yes: bool,
+    },
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_status_exit_codes() {
+        let args = |args: &str| args.split(' ').map(str::to_string).collect::<Vec<_>>();
+        let status = Cli::try_parse_from(args("backer-upper status backups.toml")).unwrap();
+        let sync = Cli::try_parse_from(args("backer-upper sync backups.toml")).unwrap();
+        let glob = Error::Pattern(glob::Pattern::new("[").unwrap_err());
+        assert_eq!(status.exit_code(&Error::Status(State::Critical)), 2);
+        assert_eq!(status.exit_code(&glob), 3);
+        assert_eq!(sync.exit_code(&glob), 15);
+        assert_eq!(status.exit_code(&Error::InvalidConfig("".to_string())), 3);
+        assert_eq!(sync.exit_code(&Error::InvalidConfig("".to_string())), 6);
+        assert!(Cli::is_status(args("backer-upper --dry-run status")));
+        assert!(!Cli::is_status(args("backer-upper sync status")));
+        assert!(!Cli::is_status(args("backer-upper")));
+    }
 }
@@ -1,39 +1,95 @@
-use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::path::Path;
 
-use crate::utils::run;
+use log::{info, warn};
 
-pub fn backup(
+use crate::archive::{resolve_globs, walk, write_archive, write_entries};
+use crate::checksum::write_checksum;
+use crate::error::Result;
+use crate::snapshot::{Snapshot, SNAPSHOT_SUFFIX};
+use crate::stream::{store, Destination, Written};
+
+pub fn backup(globs: &[String], output: &Path, gpg_id: &Option<String>) -> Result<()> {
+    backup_to(globs, &Destination::Local(output.to_path_buf()), gpg_id)?;
+    Ok(())
+}
+
+/// Print what `backup` would archive and where, without archiving anything.
+pub fn plan_backup(globs: &[String], output: &Path, gpg_id: &Option<String>) -> Result<()> {
+    let files = walk(&resolve_globs(globs)?)?;
+    for file in files.iter() {
+        println!("archive {}", file.display());
+    }
+    match gpg_id {
+        Some(gpg_id) => println!(
+            "Would archive {} path(s) to {}, encrypted for {}",
+            files.len(),
+            output.display(),
+            gpg_id
+        ),
+        None => println!(
+            "Would archive {} path(s) to {}",
+            files.len(),
+            output.display()
+        ),
+    }
+    Ok(())
+}
+
+/// Archive, compress and optionally encrypt files, streaming them straight to the destination.
+/// The SHA-256 of the result is recorded next to it.
+pub fn backup_to(
     globs: &[String],
-    output: &Path,
+    destination: &Destination,
     gpg_id: &Option<String>,
-) -> Result<(), clap::error::Error> {
-    let output = output.to_path_buf().into_os_string().into_string().unwrap();
-    let tar_gz_file = if gpg_id.is_none() {
-        output.clone()
-    } else {
-        "/tmp/backup.tar.gz".to_string()
-    };
-    let files: Vec<PathBuf> = globs
-        .iter()
-        .flat_map(|g| glob::glob(g).expect("error parsing glob"))
-        .map(Result::unwrap)
-        .map(std::fs::canonicalize)
-        .map(Result::unwrap)
-        .collect();
-    run(Command::new("tar")
-        .args(["--absolute-names", "-czf", &tar_gz_file])
-        .args(&files));
-    if gpg_id.is_some() {
-        run(Command::new("gpg").args([
-            "--encrypt",
-            "--yes",
-            "--output",
-            &output,
-            "--recipient",
-            gpg_id.as_ref().unwrap(),
-            &tar_gz_file,
-        ]));
+) -> Result<Written> {
+    let files = resolve_globs(globs)?;
+    if files.is_empty() {
+        warn!("No files matched {:?}", globs);
     }
-    Ok(())
+    let written = store(destination, gpg_id, |sink| {
+        write_archive(&files, sink)?;
+        Ok(())
+    })?;
+    write_checksum(destination, &written)?;
+    Ok(written)
+}
+
+/// Back up files and record a snapshot of them next to the backup.
+///
+/// If a parent backup is given (by name and snapshot), only the files that changed since then
+/// are archived. Otherwise a full backup is taken.
+pub fn backup_with_snapshot(
+    globs: &[String],
+    destination: &Destination,
+    gpg_id: &Option<String>,
+    parent: Option<(&str, &Snapshot)>,
+) -> Result<(Snapshot, Written)> {
+    let files = walk(&resolve_globs(globs)?)?;
+    if files.is_empty() {
+        warn!("No files matched {:?}", globs);
+    }
+    let mut snapshot = Snapshot::scan(&files, parent.map(|(_, parent)| parent))?;
+    let entries = match parent {
+        Some((name, parent)) => {
+            snapshot = snapshot.incremental_on(name, parent);
+            let entries = snapshot.changed_since(parent);
+            info!(
+                "Incremental backup on {}: {} changed, {} deleted",
+                name,
+                entries.len(),
+                snapshot.deleted.len()
+            );
+            entries
+        }
+        None => files,
+    };
+    let written = store(destination, gpg_id, |sink| {
+        write_entries(&entries, sink)?;
+        Ok(())
+    })?;
+    write_checksum(destination, &written)?;
+    store(&destination.sidecar(SNAPSHOT_SUFFIX), gpg_id, |sink| {
+        snapshot.write(sink)
+    })?;
+    Ok((snapshot, written))
 }
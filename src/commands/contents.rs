@@ -0,0 +1,75 @@
+use std::path::Path;
+
+use chrono::{DateTime, Local};
+
+use crate::archive::{list_archive, EntryInfo};
+use crate::commands::restore::{open_backup, Opened};
+use crate::error::Result;
+use crate::snapshot::Kind;
+use crate::stream::Destination;
+
+/// Format a mode like `ls -l` does, e.g. "drwxr-xr-x".
+fn format_mode(kind: Kind, mode: u32) -> String {
+    let kind = match kind {
+        Kind::File => '-',
+        Kind::Dir => 'd',
+        Kind::Symlink => 'l',
+        Kind::Other => '?',
+    };
+    let permissions: String = "rwxrwxrwx"
+        .chars()
+        .enumerate()
+        .map(|(i, c)| if mode & (0o400 >> i) != 0 { c } else { '-' })
+        .collect();
+    format!("{}{}", kind, permissions)
+}
+
+fn format_entry(entry: &EntryInfo) -> String {
+    let mtime: DateTime<Local> = entry.mtime.into();
+    let mut line = format!(
+        "{} {:>12} {} {}",
+        format_mode(entry.kind, entry.mode),
+        entry.size,
+        mtime.format("%Y-%m-%d %H:%M:%S"),
+        entry.path.display()
+    );
+    if let Some(target) = &entry.target {
+        line.push_str(&format!(" -> {}", target.display()));
+    }
+    line
+}
+
+/// List the entries of a single backup, optionally only those matching `globs`.
+pub fn list_contents(
+    backup: &Path,
+    globs: &[String],
+    gpg_id: &Option<String>,
+) -> Result<Vec<EntryInfo>> {
+    let backup = Destination::Local(backup.to_path_buf());
+    match open_backup(&backup, gpg_id, |reader| list_archive(reader, globs))? {
+        Opened::Archive(entries) => Ok(entries),
+        Opened::Manifest(manifest) => manifest.list(globs),
+    }
+}
+
+/// Print the entries of a backup without extracting it. Incremental backups only contain the
+/// files that changed since their parent, so only those are listed.
+pub fn contents(backup: &Path, globs: &Option<Vec<String>>, gpg_id: &Option<String>) -> Result<()> {
+    let globs = globs.clone().unwrap_or(vec![]);
+    for entry in list_contents(backup, &globs, gpg_id)?.iter() {
+        println!("{}", format_entry(entry));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_format_mode() {
+        assert_eq!(format_mode(Kind::Dir, 0o755), "drwxr-xr-x");
+        assert_eq!(format_mode(Kind::File, 0o100644), "-rw-r--r--");
+        assert_eq!(format_mode(Kind::Symlink, 0o777), "lrwxrwxrwx");
+    }
+}
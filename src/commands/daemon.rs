@@ -0,0 +1,391 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use chrono::{DateTime, Duration, Local, Utc};
+use log::{debug, error, info};
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+use signal_hook::flag;
+
+use crate::catalog::CatalogEntry;
+use crate::commands::sync::{check_config, load_catalog, next_run, sync_config, Outcome};
+use crate::config::{read_config_file, Config, ConfigCollection};
+use crate::error::{Error, Result};
+
+/// How long to wait before trying a config again after syncing it failed.
+const RETRY_MINUTES: i64 = 10;
+/// How long to wait before trying a config again while another sync is using its dir.
+const LOCKED_RETRY_MINUTES: i64 = 1;
+/// The longest to sleep at once, in case the clock jumps while asleep.
+const MAX_SLEEP_MINUTES: i64 = 60;
+/// How often to check for signals while sleeping.
+const POLL: std::time::Duration = std::time::Duration::from_millis(200);
+
+/// The last backup in a config's catalog.
+fn last_backup(name: &str, config: &Config) -> Result<Option<CatalogEntry>> {
+    check_config(name, config)?;
+    let (catalog, _) = load_catalog(name, config)?;
+    Ok(catalog
+        .backups(name)
+        .first()
+        .map(|backup| (*backup).clone()))
+}
+
+/// When a config should next be synced, from the last backup in its catalog.
+fn next_sync(name: &str, config: &Config, now: DateTime<Utc>) -> Result<DateTime<Utc>> {
+    next_run(config, &last_backup(name, config)?, now)
+}
+
+/// What the daemon remembers between passes over the configs.
+#[derive(Debug, Default)]
+struct Schedule {
+    /// The last backup of each config, so catalogs are only loaded again after a sync.
+    last_backups: HashMap<String, Option<CatalogEntry>>,
+    /// When to try configs again whose last sync failed or found their dir locked.
+    retries: HashMap<String, DateTime<Utc>>,
+}
+
+impl Schedule {
+    /// When a config should next be synced, loading its catalog if it isn't known yet.
+    fn next_sync(
+        &mut self,
+        name: &str,
+        config: &Config,
+        now: DateTime<Utc>,
+    ) -> Result<DateTime<Utc>> {
+        if let Some(retry) = self.retries.get(name) {
+            if *retry > now {
+                return Ok(*retry);
+            }
+        }
+        let last_backup = match self.last_backups.get(name) {
+            Some(last_backup) => last_backup.clone(),
+            None => {
+                let last_backup = last_backup(name, config)?;
+                self.last_backups
+                    .insert(name.to_string(), last_backup.clone());
+                last_backup
+            }
+        };
+        next_run(config, &last_backup, now)
+    }
+
+    /// Record how syncing a config at `now` went, so it is retried later if it failed.
+    fn synced(&mut self, name: &str, result: &Result<Outcome>, now: DateTime<Utc>) {
+        // The sync may have taken or pruned backups, so the catalog must be loaded again
+        self.last_backups.remove(name);
+        let retry = match result {
+            Ok(Outcome::AlreadyRunning { .. }) | Err(Error::Locked(..)) => {
+                Duration::minutes(LOCKED_RETRY_MINUTES)
+            }
+            Ok(_) => {
+                self.retries.remove(name);
+                return;
+            }
+            Err(_) => Duration::minutes(RETRY_MINUTES),
+        };
+        self.retries.insert(name.to_string(), now + retry);
+    }
+
+    /// Record that working out when to sync a config failed at `now`, returning when to try again.
+    fn failed(&mut self, name: &str, now: DateTime<Utc>) -> DateTime<Utc> {
+        let retry = now + Duration::minutes(RETRY_MINUTES);
+        self.retries.insert(name.to_string(), retry);
+        retry
+    }
+
+    /// Sync every config that is due, in name order, until `interrupted` says to stop. Returns
+    /// when the next config is due, or None if anything was synced, since syncing takes time and
+    /// what is due must then be worked out again.
+    fn run_due(
+        &mut self,
+        configs: &ConfigCollection,
+        now: impl Fn() -> DateTime<Utc>,
+        interrupted: impl Fn() -> bool,
+        mut sync: impl FnMut(&str, &Config) -> Result<Outcome>,
+    ) -> Result<Option<DateTime<Utc>>> {
+        let mut wake = now() + Duration::minutes(MAX_SLEEP_MINUTES);
+        let mut synced = false;
+        for name in configs.names(&None)? {
+            if interrupted() {
+                break;
+            }
+            let config = &configs.configs[name];
+            let now = now();
+            match self.next_sync(name, config, now) {
+                Ok(next) if next <= now => {
+                    synced = true;
+                    let result = sync(name, config);
+                    match &result {
+                        Ok(outcome) => println!("{}  {}", name, outcome),
+                        Err(err) => error!("Failed to sync {}: {}", name, err),
+                    }
+                    self.synced(name, &result, now);
+                }
+                Ok(next) => {
+                    debug!("{} is next due at {}", name, next);
+                    wake = wake.min(next);
+                }
+                Err(err) => {
+                    error!("Failed to work out when to sync {}: {}", name, err);
+                    wake = wake.min(self.failed(name, now));
+                }
+            }
+        }
+        Ok(if synced { None } else { Some(wake) })
+    }
+}
+
+/// Read the config file again, keeping the old configs if it can't be read. Catalogs are loaded
+/// again either way, in case backups were taken or removed by hand.
+fn reload_config(file: &Path, configs: &mut ConfigCollection, schedule: &mut Schedule) {
+    schedule.last_backups.clear();
+    match read_config_file(file) {
+        Ok(reloaded) => {
+            info!("Reloaded {:?}", file);
+            *configs = reloaded;
+            // Anything could have changed, including which catalogs the configs use
+            *schedule = Schedule::default();
+        }
+        Err(err) => error!(
+            "Failed to reload {:?}, keeping the old config: {}",
+            file, err
+        ),
+    }
+}
+
+/// Print when every config in a file will next be synced.
+fn plan_daemon(file: &Path) -> Result<()> {
+    let configs = read_config_file(file)?;
+    let names = configs.names(&None)?;
+    let width = names.iter().map(|name| name.len()).max().unwrap_or(0);
+    let now = Utc::now();
+    for name in names {
+        match next_sync(name, &configs.configs[name], now) {
+            Ok(next) if next <= now => println!("{:width$}  would sync now", name),
+            Ok(next) => println!(
+                "{:width$}  would sync at {}",
+                name,
+                next.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S")
+            ),
+            Err(err) => println!("{:width$}  failed: {}", name, err),
+        }
+    }
+    Ok(())
+}
+
+/// Keep syncing every config in a file, sleeping until the next one is due. The file is read
+/// again on SIGHUP. SIGINT or SIGTERM stops the daemon once any backup in progress is finished,
+/// and a second one stops it straight away. A dry run only prints when each config will be synced.
+pub fn daemon(file: &Path, dry_run: bool) -> Result<()> {
+    if dry_run {
+        return plan_daemon(file);
+    }
+    let reload = Arc::new(AtomicBool::new(false));
+    let stop = Arc::new(AtomicBool::new(false));
+    flag::register(SIGHUP, Arc::clone(&reload))?;
+    for signal in [SIGINT, SIGTERM] {
+        // Registered first, so it only sees the flag set by an earlier signal
+        flag::register_conditional_shutdown(signal, 1, Arc::clone(&stop))?;
+        flag::register(signal, Arc::clone(&stop))?;
+    }
+
+    let mut configs = read_config_file(file)?;
+    let mut schedule = Schedule::default();
+    info!("Syncing {:?} until stopped", file);
+    while !stop.load(Ordering::Relaxed) {
+        if reload.swap(false, Ordering::Relaxed) {
+            reload_config(file, &mut configs, &mut schedule);
+        }
+
+        let interrupted = || stop.load(Ordering::Relaxed) || reload.load(Ordering::Relaxed);
+        let Some(wake) = schedule.run_due(&configs, Utc::now, interrupted, sync_config)? else {
+            continue;
+        };
+
+        debug!("Sleeping until {}", wake);
+        while Utc::now() < wake && !interrupted() {
+            thread::sleep(POLL);
+        }
+    }
+    info!("Stopped syncing {:?}", file);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::write_config_file;
+    use crate::lock::Holder;
+    use std::path::PathBuf;
+
+    fn now() -> DateTime<Utc> {
+        "2024-03-01T12:00:00Z".parse().unwrap()
+    }
+
+    fn config(interval: &str) -> Config {
+        Config {
+            dir: "/nonexistent/backer-upper-daemon".to_string(),
+            interval: interval.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn entry(name: &str, created: DateTime<Utc>) -> CatalogEntry {
+        CatalogEntry {
+            name: format!("{}.tar.gz", name),
+            created,
+            config: name.to_string(),
+            globs: vec![],
+            size: None,
+            sha256: None,
+            gpg_id: None,
+            parent: None,
+        }
+    }
+
+    /// Three configs whose last backups are already known: `a` is due now, `b` in an hour and
+    /// `c` in a day.
+    fn setup() -> (ConfigCollection, Schedule) {
+        let mut configs = ConfigCollection::from_config("a", config("1 day"));
+        configs.configs.insert("b".to_string(), config("1 hour"));
+        configs.configs.insert("c".to_string(), config("1 day"));
+        let mut schedule = Schedule::default();
+        for (name, created) in [("a", now() - Duration::days(2)), ("b", now()), ("c", now())] {
+            schedule
+                .last_backups
+                .insert(name.to_string(), Some(entry(name, created)));
+        }
+        (configs, schedule)
+    }
+
+    /// Run a pass at `at`, returning which configs were synced and when the next one is due.
+    fn run(
+        schedule: &mut Schedule,
+        configs: &ConfigCollection,
+        at: DateTime<Utc>,
+        result: impl Fn() -> Result<Outcome>,
+    ) -> (Vec<String>, Option<DateTime<Utc>>) {
+        let mut synced = vec![];
+        let wake = schedule
+            .run_due(
+                configs,
+                || at,
+                || false,
+                |name, _| {
+                    synced.push(name.to_string());
+                    result()
+                },
+            )
+            .unwrap();
+        (synced, wake)
+    }
+
+    fn skipped() -> Result<Outcome> {
+        Ok(Outcome::Skipped {
+            last_backup: now(),
+            behind: Default::default(),
+        })
+    }
+
+    #[test]
+    fn test_run_due_picks_the_next_config() {
+        let (configs, mut schedule) = setup();
+        let (synced, wake) = run(&mut schedule, &configs, now(), skipped);
+        assert_eq!(synced, vec!["a"]);
+        assert_eq!(wake, None);
+        // Only the synced config's catalog is loaded again
+        assert!(!schedule.last_backups.contains_key("a"));
+        assert!(schedule.last_backups.contains_key("b"));
+        assert!(schedule.retries.is_empty());
+
+        schedule
+            .last_backups
+            .insert("a".to_string(), Some(entry("a", now())));
+        let (synced, wake) = run(&mut schedule, &configs, now(), skipped);
+        assert!(synced.is_empty());
+        assert_eq!(wake, Some(now() + Duration::hours(1)));
+    }
+
+    #[test]
+    fn test_run_due_retries_after_failure() {
+        let (configs, mut schedule) = setup();
+        let (synced, _) = run(&mut schedule, &configs, now(), || {
+            Err(Error::InvalidConfig("broken".to_string()))
+        });
+        assert_eq!(synced, vec!["a"]);
+        let retry = now() + Duration::minutes(RETRY_MINUTES);
+        assert_eq!(schedule.retries["a"], retry);
+
+        // The catalog can't be loaded in this test, so put back what it would say
+        schedule
+            .last_backups
+            .insert("a".to_string(), Some(entry("a", now() - Duration::days(2))));
+        let (synced, wake) = run(
+            &mut schedule,
+            &configs,
+            now() + Duration::minutes(5),
+            skipped,
+        );
+        assert!(synced.is_empty());
+        assert_eq!(wake, Some(retry));
+        let (synced, _) = run(&mut schedule, &configs, retry, skipped);
+        assert_eq!(synced, vec!["a"]);
+        assert!(schedule.retries.is_empty());
+    }
+
+    #[test]
+    fn test_run_due_retries_when_locked() {
+        let holder = Holder {
+            pid: 1,
+            hostname: "elsewhere".to_string(),
+            section: "a".to_string(),
+            since: now(),
+        };
+        for result in [
+            Ok(Outcome::AlreadyRunning {
+                holder: holder.clone(),
+            }),
+            Err(Error::Locked(PathBuf::from("/lock"), holder.clone())),
+        ] {
+            let (configs, mut schedule) = setup();
+            schedule.synced("a", &result, now());
+            assert_eq!(
+                schedule.retries["a"],
+                now() + Duration::minutes(LOCKED_RETRY_MINUTES)
+            );
+            schedule
+                .last_backups
+                .insert("a".to_string(), Some(entry("a", now() - Duration::days(2))));
+            let (synced, wake) = run(&mut schedule, &configs, now(), skipped);
+            assert!(synced.is_empty());
+            assert_eq!(wake, Some(now() + Duration::minutes(LOCKED_RETRY_MINUTES)));
+        }
+    }
+
+    #[test]
+    fn test_run_due_retries_unloadable_catalogs() {
+        let configs = ConfigCollection::from_config("a", config("1 day"));
+        let mut schedule = Schedule::default();
+        let (synced, wake) = run(&mut schedule, &configs, now(), skipped);
+        assert!(synced.is_empty());
+        assert_eq!(wake, Some(now() + Duration::minutes(RETRY_MINUTES)));
+    }
+
+    #[test]
+    fn test_reload_keeps_old_config_when_invalid() {
+        let file = std::env::temp_dir().join("backer-upper-daemon-reload.toml");
+        let (mut configs, mut schedule) = setup();
+        std::fs::write(&file, "not = [valid").unwrap();
+        reload_config(&file, &mut configs, &mut schedule);
+        assert_eq!(configs.names(&None).unwrap(), vec!["a", "b", "c"]);
+        assert!(schedule.last_backups.is_empty());
+
+        write_config_file(&ConfigCollection::from_config("d", config("1 day")), &file).unwrap();
+        reload_config(&file, &mut configs, &mut schedule);
+        assert_eq!(configs.names(&None).unwrap(), vec!["d"]);
+        assert!(schedule.last_backups.is_empty());
+    }
+}
@@ -0,0 +1,124 @@
+use std::path::Path;
+
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+
+use crate::commands::sync::{load_catalog, pruned_by_next_backup};
+use crate::config::{read_config_file, Config};
+use crate::error::Result;
+
+/// A single backup, as shown by `list`.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Listing {
+    pub section: String,
+    pub name: String,
+    pub created: DateTime<Utc>,
+    pub age_seconds: i64,
+    pub size: Option<u64>,
+    /// Whether the backup will be pruned once the section takes its next backup.
+    pub prune: bool,
+}
+
+/// Format an age as its two largest units, like "3d 4h".
+pub(crate) fn format_age(age: Duration) -> String {
+    let seconds = age.num_seconds().max(0);
+    let units = [
+        ("d", seconds / 86400),
+        ("h", seconds / 3600 % 24),
+        ("m", seconds / 60 % 60),
+        ("s", seconds % 60),
+    ];
+    let first = units
+        .iter()
+        .position(|(_, count)| *count > 0)
+        .unwrap_or(units.len() - 1);
+    units[first..]
+        .iter()
+        .take(2)
+        .map(|(unit, count)| format!("{}{}", count, unit))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/// List the backups of a config, newest first.
+pub fn list_config(name: &str, config: &Config) -> Result<Vec<Listing>> {
+    let (catalog, _) = load_catalog(name, config)?;
+    let pruned = pruned_by_next_backup(name, config, &catalog)?;
+    let now = Utc::now();
+    Ok(catalog
+        .backups(name)
+        .into_iter()
+        .map(|backup| Listing {
+            section: name.to_string(),
+            name: backup.name.clone(),
+            created: backup.created,
+            age_seconds: (now - backup.created).num_seconds(),
+            size: backup.size,
+            prune: pruned.contains(&backup.name),
+        })
+        .collect())
+}
+
+fn print_listings(listings: &[Listing]) {
+    let width = listings
+        .iter()
+        .map(|listing| listing.name.len())
+        .max()
+        .unwrap_or(0);
+    for listing in listings.iter() {
+        println!(
+            "{:width$}  {}  {:>7}  {:>12}{}",
+            listing.name,
+            listing.created.format("%Y-%m-%d %H:%M:%S"),
+            format_age(Duration::seconds(listing.age_seconds)),
+            listing
+                .size
+                .map(|size| size.to_string())
+                .unwrap_or("-".to_string()),
+            if listing.prune { "  prune" } else { "" },
+        );
+    }
+}
+
+/// List the backups of every config in a file, or just the one named `section`.
+pub fn list(file: &Path, section: &Option<String>, json: bool) -> Result<()> {
+    let configs = read_config_file(file)?;
+    let names = configs.names(section)?;
+    let mut sections = vec![];
+    for name in names.into_iter() {
+        sections.push((name, list_config(name, &configs.configs[name])?));
+    }
+    if json {
+        let listings: Vec<&Listing> = sections
+            .iter()
+            .flat_map(|(_, listings)| listings.iter())
+            .collect();
+        println!("{}", serde_json::to_string_pretty(&listings)?);
+        return Ok(());
+    }
+    for (i, (name, listings)) in sections.iter().enumerate() {
+        if i > 0 {
+            println!();
+        }
+        println!("{}:", name);
+        print_listings(listings);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_format_age() {
+        assert_eq!(format_age(Duration::seconds(0)), "0s");
+        assert_eq!(format_age(Duration::seconds(42)), "42s");
+        assert_eq!(format_age(Duration::seconds(3723)), "1h 2m");
+        assert_eq!(
+            format_age(Duration::seconds(86400 * 3 + 3600 * 4 + 5)),
+            "3d 4h"
+        );
+        assert_eq!(format_age(Duration::seconds(86400)), "1d 0h");
+    }
+}
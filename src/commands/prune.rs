@@ -0,0 +1,106 @@
+use std::path::Path;
+
+use chrono::Utc;
+use log::error;
+
+use crate::catalog::Catalog;
+use crate::commands::sync::{load_catalog, remove_backups};
+use crate::config::{read_config_file, Config};
+use crate::error::{Error, Result};
+use crate::lock::Lock;
+use crate::repository::Repository;
+use crate::retention::{decide, Decision};
+
+/// Decide which of a config's backups to keep under its retention rules, without taking a new
+/// backup, and remove the rest if `remove` is set. Returns the decisions, or None if the config
+/// keeps every backup.
+pub fn prune_config(name: &str, config: &Config, remove: bool) -> Result<Option<Vec<Decision>>> {
+    let dir = Path::new(&config.dir);
+    let _lock = if remove {
+        Some(Lock::acquire_both(&config.host, dir, name)?)
+    } else {
+        None
+    };
+    let (mut catalog, imported) = load_catalog(name, config)?;
+    let Some(decisions) = decide(name, config, &catalog, Utc::now())? else {
+        return Ok(None);
+    };
+    if !remove {
+        return Ok(Some(decisions));
+    }
+    if imported {
+        catalog.save(&Catalog::destination(&config.host, dir))?;
+    }
+    let redundant: Vec<String> = decisions
+        .iter()
+        .filter(|decision| !decision.keep)
+        .map(|decision| decision.name.clone())
+        .collect();
+    remove_backups(config, &mut catalog, &redundant)?;
+    if config.repository {
+        Repository::new(dir, &config.gpg_id).collect_garbage()?;
+    }
+    Ok(Some(decisions))
+}
+
+fn print_decisions(decisions: &[Decision]) {
+    let width = decisions
+        .iter()
+        .map(|decision| decision.name.len())
+        .max()
+        .unwrap_or(0);
+    for decision in decisions.iter() {
+        println!(
+            "  {:6}  {:width$}  {}",
+            if decision.keep { "keep" } else { "remove" },
+            decision.name,
+            decision.reasons.join(", "),
+        );
+    }
+}
+
+/// Apply the retention rules of every config in a file, or just the one named `section`. Unless
+/// `yes` is set, only print which backups would be kept and removed. Every config is pruned even
+/// if another one fails, and the command fails at the end if any did.
+pub fn prune(file: &Path, section: &Option<String>, yes: bool) -> Result<()> {
+    let configs = read_config_file(file)?;
+    let names = configs.names(section)?;
+    let total = names.len();
+    let mut removed = 0;
+    let mut failed = 0;
+    for (i, name) in names.into_iter().enumerate() {
+        if i > 0 {
+            println!();
+        }
+        println!("{}:", name);
+        match prune_config(name, &configs.configs[name], yes) {
+            Ok(Some(decisions)) => {
+                print_decisions(&decisions);
+                removed += decisions.iter().filter(|decision| !decision.keep).count();
+            }
+            Ok(None) => println!("  keeps every backup, as it has no copies or retention"),
+            Err(err) => {
+                error!("Failed to prune {}: {}", name, err);
+                println!("  failed: {}", err);
+                failed += 1;
+            }
+        }
+    }
+    if removed > 0 {
+        println!();
+        if yes {
+            println!("Removed {} backup(s)", removed);
+        } else {
+            // The preview doesn't take the locks, so a sync could take or prune backups meanwhile
+            println!(
+                "Run again with --yes to remove {} backup(s). A sync running in the meantime \
+                 can change which.",
+                removed
+            );
+        }
+    }
+    if failed > 0 {
+        return Err(Error::Prune { failed, total });
+    }
+    Ok(())
+}
@@ -1,32 +1,295 @@
-use std::path::Path;
-use std::process::Command;
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::io::Read;
+use std::path::{Path, PathBuf};
 
-use clap::error::Error;
+use log::{debug, info};
 
-use crate::utils::run;
+use crate::archive::{
+    archive_name, compile_globs, extract_archive, is_requested, Action, Report, Target,
+};
+use crate::commands::contents::list_contents;
+use crate::error::{Error, Result};
+use crate::repository::{Manifest, Repository};
+use crate::snapshot::{Kind, Snapshot, SNAPSHOT_SUFFIX};
+use crate::stream::Destination;
 
-pub fn restore(
+/// Find every backup needed to restore this one, starting with the last full backup. Parents are
+/// read from snapshots, which could have been tampered with, so a parent that isn't a file next to
+/// the backup or that comes up twice is an error rather than something to follow.
+fn chain(backup: &Path, gpg_id: &Option<String>) -> Result<Vec<(PathBuf, Option<Snapshot>)>> {
+    let mut chain = vec![];
+    let mut visited = HashSet::new();
+    let mut next = Some(backup.to_path_buf());
+    while let Some(backup) = next {
+        visited.extend(backup.file_name().map(OsStr::to_os_string));
+        let destination = Destination::Local(backup.clone());
+        let snapshot = Snapshot::load(&destination, gpg_id)?;
+        next = match snapshot
+            .as_ref()
+            .and_then(|snapshot| snapshot.parent.as_ref())
+        {
+            Some(parent) => {
+                let name = Path::new(parent);
+                if name.file_name() != Some(name.as_os_str()) || !visited.insert(name.into()) {
+                    return Err(Error::Corrupt(
+                        destination.sidecar(SNAPSHOT_SUFFIX).path().to_path_buf(),
+                        format!(
+                            "its parent {:?} is not an earlier backup next to it",
+                            parent
+                        ),
+                    ));
+                }
+                Some(backup.with_file_name(name))
+            }
+            None => None,
+        };
+        chain.push((backup, snapshot));
+    }
+    chain.reverse();
+    Ok(chain)
+}
+
+/// A backup that has been opened, which is either an archive or a manifest.
+pub(crate) enum Opened<T> {
+    /// The result of reading an archive.
+    Archive(T),
+    Manifest(Manifest),
+}
+
+/// Open a backup, decrypting it if necessary. Archives are passed to `read_archive` as they are
+/// streamed, while manifests are read and returned.
+pub(crate) fn open_backup<T>(
+    backup: &Destination,
+    gpg_id: &Option<String>,
+    read_archive: impl FnOnce(&mut dyn Read) -> Result<T>,
+) -> Result<Opened<T>> {
+    let mut source = backup.open(gpg_id)?;
+    let mut start = [0; 1];
+    let opened = source.read_exact(&mut start).map(|_| {
+        let mut reader = (&start[..]).chain(&mut source);
+        if Manifest::is_manifest(&start) {
+            Manifest::read(reader).map(Opened::Manifest)
+        } else {
+            read_archive(&mut reader).map(Opened::Archive)
+        }
+    });
+    // If gpg failed, the reason it gives is more useful than a truncated archive
+    source.finish()?;
+    opened?
+}
+
+/// Extract an archive, or restore a manifest from its repository.
+fn extract(
+    backup: &Path,
+    files: &[String],
+    gpg_id: &Option<String>,
+    target: &Target,
+    report: &mut Report,
+) -> Result<()> {
+    let opened = open_backup(
+        &Destination::Local(backup.to_path_buf()),
+        gpg_id,
+        |reader| extract_archive(reader, files, target, report),
+    )?;
+    if let Opened::Manifest(manifest) = opened {
+        Repository::of_manifest(backup, gpg_id).restore(&manifest, files, target, report)?;
+    }
+    Ok(())
+}
+
+/// Remove files that an incremental backup recorded as deleted since its parent.
+fn remove_deleted(
+    snapshot: &Snapshot,
+    files: &[String],
+    target: &Target,
+    report: &mut Report,
+) -> Result<()> {
+    let patterns = compile_globs(files)?;
+    for deleted in snapshot.deleted.iter() {
+        if !is_requested(&archive_name(deleted), &patterns) {
+            continue;
+        }
+        let Some(path) = target.path_of(deleted) else {
+            continue;
+        };
+        if !target.prepare_removal(&path, report)? {
+            continue;
+        }
+        debug!("Removing deleted file {:?}", path);
+        let removed = match std::fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.is_dir() => std::fs::remove_dir_all(&path),
+            Ok(_) => std::fs::remove_file(&path),
+            Err(e) => Err(e),
+        };
+        match removed {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            removed => removed?,
+        }
+    }
+    Ok(())
+}
+
+/// Restore files from a backup to where they were backed up from.
+pub fn restore(backup: &Path, files: &Option<Vec<String>>, gpg_id: &Option<String>) -> Result<()> {
+    restore_to(backup, files, gpg_id, &Target::new(Path::new("/")))?;
+    Ok(())
+}
+
+/// Restore files from a backup to `target`, reporting what happened to files that were already
+/// there. If the backup is incremental, the full backup and every increment leading up to it are
+/// restored in order, so files end up as they were when the backup was taken.
+pub fn restore_to(
     backup: &Path,
     files: &Option<Vec<String>>,
     gpg_id: &Option<String>,
-) -> Result<(), Error> {
-    let mut backup_file = backup
-        .as_os_str()
-        .to_str()
-        .expect("error parsing path to backup file");
-    if gpg_id.is_some() {
-        run(Command::new("gpg").args([
-            "--decrypt",
-            "--yes",
-            "--output",
-            "/tmp/backup.tar.gz",
-            backup_file,
-        ]));
-        backup_file = "/tmp/backup.tar.gz";
+    target: &Target,
+) -> Result<Report> {
+    let files = files.clone().unwrap_or(vec![]);
+    let mut report = Report::default();
+    for (backup, snapshot) in chain(backup, gpg_id)?.iter() {
+        info!("Restoring {:?}", backup);
+        extract(backup, &files, gpg_id, target, &mut report)?;
+        if let Some(snapshot) = snapshot {
+            remove_deleted(snapshot, &files, target, &mut report)?;
+        }
     }
+    Ok(report)
+}
+
+/// What restoring a backup would do to a single path.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Step {
+    /// An entry of the backup would be restored to the path.
+    Restore(PathBuf, Action),
+    /// A file that the backup recorded as deleted would be removed from the path.
+    Remove(PathBuf, Action),
+}
+
+/// Work out what restoring a backup to `target` would do, without restoring anything, by making
+/// the same decisions about existing files that a restore would. The steps are listed for each
+/// backup in the order they would be restored.
+pub fn plan_restore(
+    backup: &Path,
+    files: &Option<Vec<String>>,
+    gpg_id: &Option<String>,
+    target: &Target,
+) -> Result<Vec<(PathBuf, Vec<Step>)>> {
     let files = files.clone().unwrap_or(vec![]);
-    run(Command::new("tar")
-        .args(["--absolute-names", "-xzf", backup_file])
-        .args(&files));
-    Ok(())
+    let patterns = compile_globs(&files)?;
+    let mut report = Report::default();
+    let mut plan = vec![];
+    for (backup, snapshot) in chain(backup, gpg_id)?.iter() {
+        let mut steps = vec![];
+        for entry in list_contents(backup, &files, gpg_id)?.iter() {
+            let Some(path) = target.path_of(&entry.path) else {
+                continue;
+            };
+            let action = target.decide(&path, entry.kind == Kind::Dir, entry.mtime, &report)?;
+            report.plan(&path, &action);
+            steps.push(Step::Restore(path, action));
+        }
+        let deleted = snapshot.iter().flat_map(|snapshot| snapshot.deleted.iter());
+        for deleted in deleted {
+            if !is_requested(&archive_name(deleted), &patterns) {
+                continue;
+            }
+            let Some(path) = target.path_of(deleted) else {
+                continue;
+            };
+            if let Some(action) = target.decide_removal(&path, &report)? {
+                report.plan_removal(&path);
+                steps.push(Step::Remove(path, action));
+            }
+        }
+        plan.push((backup.clone(), steps));
+    }
+    Ok(plan)
+}
+
+/// Print what restoring a backup would do, as planned by `plan_restore`.
+pub fn print_plan(plan: &[(PathBuf, Vec<Step>)]) {
+    for (backup, steps) in plan.iter() {
+        println!("From {}:", backup.display());
+        for step in steps.iter() {
+            let (verb, path, action) = match step {
+                Step::Restore(path, Action::Restore) => ("restore", path, &Action::Restore),
+                Step::Restore(path, action) => ("replace", path, action),
+                Step::Remove(path, action) => ("remove", path, action),
+            };
+            match action {
+                Action::Merge => {}
+                Action::Restore | Action::Replace => println!("  {} {}", verb, path.display()),
+                Action::Skip => println!("  keep {}", path.display()),
+                Action::Preserve(backup) => println!(
+                    "  {} {} (keeping it as {})",
+                    verb,
+                    path.display(),
+                    backup.display()
+                ),
+                Action::Ask => println!("  {} {} if confirmed", verb, path.display()),
+            }
+        }
+    }
+}
+
+/// Print which existing files were replaced, skipped or preserved by a restore.
+pub fn print_report(report: &Report) {
+    if !report.replaced.is_empty() {
+        println!("Replaced {} existing file(s):", report.replaced.len());
+        for path in report.replaced.iter() {
+            println!("  {}", path.display());
+        }
+    }
+    if !report.skipped.is_empty() {
+        println!("Skipped {} existing file(s):", report.skipped.len());
+        for path in report.skipped.iter() {
+            println!("  {}", path.display());
+        }
+    }
+    if !report.preserved.is_empty() {
+        println!("Preserved {} existing file(s):", report.preserved.len());
+        for (path, backup) in report.preserved.iter() {
+            println!("  {} -> {}", path.display(), backup.display());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Write a snapshot saying that `name` in `dir` is an increment on `parent`.
+    fn write_snapshot(dir: &Path, name: &str, parent: &str) {
+        let snapshot = Snapshot {
+            parent: Some(parent.to_string()),
+            ..Default::default()
+        };
+        let file = std::fs::File::create(dir.join(format!("{}{}", name, SNAPSHOT_SUFFIX))).unwrap();
+        snapshot.write(file).unwrap();
+    }
+
+    #[test]
+    fn test_chain_rejects_bad_parents() {
+        let dir = std::env::temp_dir().join("backer-upper-restore-chain");
+        if dir.exists() {
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+        std::fs::create_dir_all(&dir).unwrap();
+        let chain_of = |name: &str| chain(&dir.join(name), &None);
+
+        write_snapshot(&dir, "b", "a");
+        let names: Vec<PathBuf> = chain_of("b").unwrap().into_iter().map(|(b, _)| b).collect();
+        assert_eq!(names, vec![dir.join("a"), dir.join("b")]);
+
+        write_snapshot(&dir, "self", "self");
+        assert!(matches!(chain_of("self"), Err(Error::Corrupt(..))));
+        write_snapshot(&dir, "x", "y");
+        write_snapshot(&dir, "y", "x");
+        assert!(matches!(chain_of("x"), Err(Error::Corrupt(..))));
+        write_snapshot(&dir, "up", "../a");
+        assert!(matches!(chain_of("up"), Err(Error::Corrupt(..))));
+        write_snapshot(&dir, "nested", "dir/a");
+        assert!(matches!(chain_of("nested"), Err(Error::Corrupt(..))));
+    }
 }
@@ -0,0 +1,187 @@
+use std::path::Path;
+
+use chrono::{DateTime, Local, Utc};
+
+use crate::commands::list::format_age;
+use crate::commands::sync::{behind, check_config, load_catalog, next_run, Behind};
+use crate::config::{read_config_file, Config};
+use crate::error::{Error, Result};
+pub use crate::health::State;
+use crate::repository::Repository;
+
+/// The health of a config's backups, as shown by `status`.
+#[derive(Debug, PartialEq)]
+pub struct Status {
+    pub state: State,
+    /// Why the state isn't OK.
+    pub reasons: Vec<String>,
+    pub last_backup: Option<DateTime<Utc>>,
+    /// When the next backup will be taken, if sync is run then.
+    pub next_run: DateTime<Utc>,
+    pub backups: usize,
+    /// The total size of the backups as stored, from the catalog. For a repository, this also
+    /// counts the chunks the manifests use.
+    pub total_size: u64,
+}
+
+/// Decide how healthy a config's backups are. Having no backups, or none since `alert_after`, is
+/// critical, and having missed a scheduled backup before the one that is due is a warning.
+fn assess(last_backup: &Option<DateTime<Utc>>, behind: &Behind) -> (State, Vec<String>) {
+    if last_backup.is_none() {
+        return (State::Critical, vec!["there are no backups".to_string()]);
+    }
+    let mut state = State::Ok;
+    let mut reasons = vec![];
+    if behind.overdue_since.is_some() {
+        state = State::Critical;
+        reasons.push("the last backup is older than alert_after".to_string());
+    }
+    if behind.missed > 0 {
+        state = state.max(State::Warn);
+        reasons.push(format!("missed {} scheduled backup(s)", behind.missed));
+    }
+    (state, reasons)
+}
+
+/// Check the health of a config's backups at `now`.
+pub fn status_config(name: &str, config: &Config, now: DateTime<Utc>) -> Result<Status> {
+    check_config(name, config)?;
+    let (catalog, _) = load_catalog(name, config)?;
+    let backups = catalog.backups(name);
+    let last_backup = backups.first().map(|backup| (*backup).clone());
+    let behind = behind(name, config, &last_backup, now)?;
+    let (state, reasons) = assess(&last_backup.as_ref().map(|backup| backup.created), &behind);
+    // The catalog only has the size of each manifest
+    let mut total_size = backups.iter().filter_map(|backup| backup.size).sum();
+    if config.repository {
+        let names: Vec<&str> = backups.iter().map(|backup| backup.name.as_str()).collect();
+        total_size +=
+            Repository::new(Path::new(&config.dir), &config.gpg_id).chunks_size(&names)?;
+    }
+    Ok(Status {
+        state,
+        reasons,
+        last_backup: last_backup.as_ref().map(|backup| backup.created),
+        next_run: next_run(config, &last_backup, now)?,
+        backups: backups.len(),
+        total_size,
+    })
+}
+
+fn print_status(config: &Config, status: &Status, now: DateTime<Utc>) {
+    let local = |time: DateTime<Utc>| time.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S");
+    let every = match (config.interval.as_str(), &config.schedule) {
+        ("", Some(schedule)) => format!("schedule {}", schedule),
+        (interval, None) => format!("interval {}", interval),
+        (interval, Some(schedule)) => format!("interval {}, schedule {}", interval, schedule),
+    };
+    match status.last_backup {
+        Some(last_backup) => println!(
+            "  last backup  {}, {} ago ({})",
+            local(last_backup),
+            format_age(now - last_backup),
+            every
+        ),
+        None => println!("  last backup  never ({})", every),
+    }
+    if status.next_run <= now {
+        println!("  next due     now");
+    } else {
+        println!(
+            "  next due     {}, in {}",
+            local(status.next_run),
+            format_age(status.next_run - now)
+        );
+    }
+    match config.copies {
+        Some(copies) => println!("  copies       {} of {}", status.backups, copies),
+        None => println!("  copies       {}", status.backups),
+    }
+    println!("  total size   {} bytes", status.total_size);
+}
+
+/// Show the health of every config in a file, or just the one named `section`. Fails with the
+/// worst state if any config isn't OK.
+pub fn status(file: &Path, section: &Option<String>) -> Result<()> {
+    let configs = read_config_file(file)?;
+    let names = configs.names(section)?;
+    let now = Utc::now();
+    let statuses: Vec<(&String, Result<Status>)> = names
+        .into_iter()
+        .map(|name| (name, status_config(name, &configs.configs[name], now)))
+        .collect();
+    let states: Vec<State> = statuses
+        .iter()
+        .map(|(_, status)| match status {
+            Ok(status) => status.state,
+            Err(_) => State::Unknown,
+        })
+        .collect();
+    let worst = states.iter().copied().max().unwrap_or(State::Ok);
+
+    // The first line is the summary that Nagios shows
+    let counts: Vec<String> = [State::Critical, State::Unknown, State::Warn, State::Ok]
+        .iter()
+        .filter_map(|state| {
+            let count = states.iter().filter(|other| *other == state).count();
+            (count > 0).then(|| format!("{} {}", count, state))
+        })
+        .collect();
+    println!(
+        "{} - {} section(s): {}",
+        worst,
+        states.len(),
+        counts.join(", ")
+    );
+    for (name, status) in statuses.iter() {
+        println!();
+        match status {
+            Ok(status) => {
+                let mut state = status.state.to_string();
+                for reason in status.reasons.iter() {
+                    state += &format!(", {}", reason);
+                }
+                println!("{}: {}", name, state);
+                print_status(&configs.configs[*name], status, now);
+            }
+            Err(err) => println!("{}: {}, {}", name, State::Unknown, err),
+        }
+    }
+    if worst != State::Ok {
+        return Err(Error::Status(worst));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_assess() {
+        let now = Utc::now();
+        assert_eq!(
+            assess(&None, &Behind::default()),
+            (State::Critical, vec!["there are no backups".to_string()])
+        );
+        assert_eq!(assess(&Some(now), &Behind::default()), (State::Ok, vec![]));
+        let missed = Behind {
+            missed: 2,
+            overdue_since: None,
+        };
+        assert_eq!(
+            assess(&Some(now), &missed),
+            (
+                State::Warn,
+                vec!["missed 2 scheduled backup(s)".to_string()]
+            )
+        );
+        let overdue = Behind {
+            overdue_since: Some(now - Duration::days(3)),
+            ..missed
+        };
+        assert_eq!(assess(&Some(now), &overdue).0, State::Critical);
+    }
+}
@@ -1,56 +1,37 @@
+use std::fmt;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 
-use chrono::{DateTime, Days, Duration, Months, TimeZone, Utc};
-use clap::error::Error;
-use log::{debug, trace};
+use chrono::format::{self, Parsed, StrftimeItems};
+use chrono::{DateTime, Duration, Local, Utc};
+use log::{debug, error, trace, warn};
 use regex::Regex;
 
-use crate::commands::backup::backup;
+use crate::archive::resolve_globs;
+use crate::catalog::{Catalog, CatalogEntry};
+use crate::checksum::SHA256_SUFFIX;
+use crate::commands::backup::{backup_to, backup_with_snapshot};
 use crate::config::{read_config_file, Config};
+use crate::error::{Error, Result};
+use crate::interval::Interval;
+use crate::lock::{Holder, Lock};
+use crate::repository::Repository;
+use crate::retention::{parse_size, redundant};
+use crate::schedule::{Cron, Window};
+use crate::snapshot::{Snapshot, SNAPSHOT_SUFFIX};
+use crate::stream::{Destination, PARTIAL_SUFFIX};
 use crate::utils::run;
 
-/// Offset a DateTime<T> by a specially formatted interval string.
-///
-/// The interval might require use of Months or Days, so we cannot simply return a Duration.
-fn offset_by_interval<T: TimeZone>(now: DateTime<T>, interval: &str) -> DateTime<T> {
-    let pattern = Regex::new(r"^([0-9]+)\W*([Mwdhms]|month|months|week|weeks|day|days|hour|hours|minute|minutes|second|seconds)$").unwrap();
-    let captures = pattern
-        .captures(interval)
-        .unwrap_or_else(|| panic!("invalid date string {}", interval));
-    let count: u32 = captures[1].parse().unwrap();
-    let unit: &str = &captures[2];
-    let _now = now.clone(); // hack to sneak the value around the borrow
-    let offset = match unit {
-        "M" | "month" | "months" => now - Months::new(count),
-        "w" | "week" | "weeks" => now - Duration::weeks(count.into()),
-        "d" | "day" | "days" => now - Days::new(count.into()),
-        "h" | "hour" | "hours" => now - Duration::hours(count.into()),
-        "m" | "minute" | "minutes" => now - Duration::minutes(count.into()),
-        "s" | "second" | "seconds" => now - Duration::seconds(count.into()),
-        _ => panic!("invalid time unit {}", unit),
-    };
-    debug!(
-        "Offset {:?} by {} to get {:?}",
-        &_now.clone(),
-        &(_now - offset.clone()),
-        &offset
-    );
-    offset
-}
-
 /// Parse the output of the `ls -At --full-time` command into file names and last modified times.
+///
+/// Lines that cannot be parsed are ignored.
 fn parse_ls(raw: &str) -> Vec<(String, DateTime<Utc>)> {
     let pattern = Regex::new(r"[drwx\-]{10} [0-9]+\W+\w+\W+\w+\W+[0-9]+ ([0-9]{4}-[0-9]{2}-[0-9]{2} [0-9]{2}:[0-9]{2}:[0-9]{2}.[0-9]+ [+\-][0-9]{4}) (.+)").unwrap();
     pattern
         .captures_iter(raw)
-        .map(|captures| {
-            (
-                captures[2].to_string(),
-                DateTime::parse_from_str(&captures[1], "%Y-%m-%d %H:%M:%S.%f %z")
-                    .unwrap()
-                    .with_timezone(&Utc),
-            )
+        .filter_map(|captures| {
+            let time = DateTime::parse_from_str(&captures[1], "%Y-%m-%d %H:%M:%S.%f %z").ok()?;
+            Some((captures[2].to_string(), time.with_timezone(&Utc)))
         })
         .collect()
 }
@@ -75,18 +56,6 @@ fn time_matches(name: &str, time: &DateTime<Utc>, format: &str) -> bool {
     false
 }
 
-/// Find the most recent file matching a format string. Assumes that the files are already in
-/// sorted order.
-fn find_most_recent_matching(
-    files: &[(String, DateTime<Utc>)],
-    format: &str,
-) -> Option<(String, DateTime<Utc>)> {
-    files
-        .iter()
-        .find(|(name, time)| time_matches(name, time, format))
-        .cloned()
-}
-
 /// Filter out any files that were not plausibly generated by this sync process.
 fn filter_backups(files: &[(String, DateTime<Utc>)], format: &str) -> Vec<(String, DateTime<Utc>)> {
     files
@@ -96,221 +65,949 @@ fn filter_backups(files: &[(String, DateTime<Utc>)], format: &str) -> Vec<(Strin
         .collect()
 }
 
-pub fn sync_config(name: &str, config: &Config) -> Result<Option<PathBuf>, Error> {
+/// What happened to a single config when it was synced.
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    /// A new backup was created, and any redundant backups were removed.
+    Created {
+        backup: PathBuf,
+        pruned: Vec<PathBuf>,
+        behind: Behind,
+    },
+    /// The last backup was too recent, so nothing was done.
+    Skipped {
+        last_backup: DateTime<Utc>,
+        behind: Behind,
+    },
+    /// A backup is due, but it is outside the time of day backups may run in.
+    OutsideWindow { window: String, behind: Behind },
+    /// Another sync is using the config's dir, so nothing was done.
+    AlreadyRunning { holder: Holder },
+    /// This is a dry run, and a new backup would have been created from `files`.
+    Planned {
+        backup: PathBuf,
+        files: Vec<PathBuf>,
+        /// The backup the new one would be an increment on.
+        parent: Option<String>,
+        pruned: Vec<PathBuf>,
+        behind: Behind,
+    },
+}
+
+impl Outcome {
+    /// The new backup, if one was created.
+    pub fn into_backup(self) -> Option<PathBuf> {
+        match self {
+            Outcome::Created { backup, .. } => Some(backup),
+            Outcome::Skipped { .. }
+            | Outcome::OutsideWindow { .. }
+            | Outcome::AlreadyRunning { .. }
+            | Outcome::Planned { .. } => None,
+        }
+    }
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::Created {
+                backup,
+                pruned,
+                behind,
+            } => {
+                write!(f, "created {}", backup.display())?;
+                if !pruned.is_empty() {
+                    write!(f, ", pruned {} old backup(s)", pruned.len())?;
+                }
+                write!(f, "{}", behind)
+            }
+            Outcome::Skipped {
+                last_backup,
+                behind,
+            } => {
+                write!(f, "skipped, last backup was at {}{}", last_backup, behind)
+            }
+            Outcome::OutsideWindow { window, behind } => {
+                write!(f, "skipped, outside of its window {}{}", window, behind)
+            }
+            Outcome::AlreadyRunning { holder } => {
+                write!(f, "skipped, already running: {}", holder)
+            }
+            Outcome::Planned {
+                backup,
+                files,
+                parent,
+                pruned,
+                behind,
+            } => {
+                write!(
+                    f,
+                    "would create {} from {} path(s)",
+                    backup.display(),
+                    files.len()
+                )?;
+                if let Some(parent) = parent {
+                    write!(f, " as an increment on {}", parent)?;
+                }
+                if !pruned.is_empty() {
+                    write!(f, ", would prune {} old backup(s)", pruned.len())?;
+                }
+                write!(f, "{}", behind)
+            }
+        }
+    }
+}
+
+/// How far a config had fallen behind when it was synced.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Behind {
+    /// How many times a backup fell due since the last one without being taken, not counting
+    /// the one that is due now.
+    pub missed: usize,
+    /// When the last backup was taken, if that was longer ago than `alert_after`.
+    pub overdue_since: Option<DateTime<Utc>>,
+}
+
+/// Written after the rest of an outcome, so it starts with a comma if it isn't empty.
+impl fmt::Display for Behind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.missed > 0 {
+            write!(f, ", missed {} scheduled backup(s)", self.missed)?;
+        }
+        if let Some(overdue_since) = self.overdue_since {
+            write!(
+                f,
+                ", last backup at {} is older than alert_after",
+                overdue_since
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// The most missed backups to count, so that a short interval after a long time doesn't take
+/// long to count.
+const MAX_MISSED: usize = 10_000;
+
+/// Check that a config's settings make sense together.
+pub(crate) fn check_config(name: &str, config: &Config) -> Result<()> {
+    if config.repository && config.host.is_some() {
+        return Err(Error::InvalidConfig(format!(
+            "{} is a repository, which must be in a local dir",
+            name
+        )));
+    }
+    if config.repository && config.incremental.is_some() {
+        return Err(Error::InvalidConfig(format!(
+            "{} is a repository, which cannot also be incremental",
+            name
+        )));
+    }
+    if config.interval.is_empty() && config.schedule.is_none() {
+        return Err(Error::InvalidConfig(format!(
+            "{} needs an interval or a schedule",
+            name
+        )));
+    }
+    // Find out about a bad interval or limit before taking a backup rather than after it
+    if !config.interval.is_empty() {
+        config.interval.parse::<Interval>()?;
+    }
+    if let Some(schedule) = &config.schedule {
+        schedule.parse::<Cron>()?;
+    }
+    if let Some(window) = &config.window {
+        window.parse::<Window>()?;
+    }
+    for interval in [
+        &config.alert_after,
+        &config.retention.keep_within,
+        &config.retention.max_age,
+    ]
+    .into_iter()
+    .flatten()
+    {
+        interval.parse::<Interval>()?;
+    }
+    if let Some(max_total_size) = &config.retention.max_total_size {
+        parse_size(max_total_size)?;
+    }
+    Ok(())
+}
+
+/// When a config's backups fall due, with its interval and schedule parsed once so that many
+/// due times can be worked out quickly.
+struct Timing<'a> {
+    config: &'a Config,
+    interval: Option<Interval>,
+    schedule: Option<Cron>,
+}
+
+impl<'a> Timing<'a> {
+    fn new(config: &'a Config) -> Result<Timing<'a>> {
+        Ok(Timing {
+            config,
+            interval: match config.interval.as_str() {
+                "" => None,
+                interval => Some(interval.parse()?),
+            },
+            schedule: config.schedule.as_deref().map(str::parse).transpose()?,
+        })
+    }
+
+    /// When the next backup is due after one taken at `last_backup`. A backup is due once
+    /// `interval` has passed since the last one or, if there is a schedule, at the first time in
+    /// the schedule after that.
+    fn due_after(&self, last_backup: DateTime<Utc>) -> Result<DateTime<Utc>> {
+        let mut due = last_backup;
+        if let Some(interval) = &self.interval {
+            due = interval.after(due).ok_or_else(|| {
+                Error::Interval(
+                    self.config.interval.clone(),
+                    "it is out of range".to_string(),
+                )
+            })?;
+        }
+        if let Some(schedule) = &self.schedule {
+            // A time in the schedule exactly `interval` after the last backup counts, but the
+            // time of the last backup itself doesn't
+            let after = if self.interval.is_none() {
+                due
+            } else {
+                due - Duration::seconds(1)
+            };
+            due = schedule
+                .next_after(&after.with_timezone(&Local))
+                .ok_or_else(|| {
+                    Error::InvalidConfig(format!(
+                        "the schedule {:?} never comes round",
+                        self.config.schedule.as_deref().unwrap_or_default()
+                    ))
+                })?
+                .with_timezone(&Utc);
+        }
+        Ok(due)
+    }
+}
+
+/// When a config's next backup is due, or None if it has never taken one and so is due now.
+pub(crate) fn next_due(
+    config: &Config,
+    last_backup: &Option<CatalogEntry>,
+) -> Result<Option<DateTime<Utc>>> {
+    let Some(last_backup) = last_backup else {
+        return Ok(None);
+    };
+    debug!("Last backup was at {}", last_backup.created);
+    Ok(Some(Timing::new(config)?.due_after(last_backup.created)?))
+}
+
+/// How many times a backup fell due after `last_backup` and before `now`, not counting the last
+/// time, which the next backup makes up for. Counting stops at `MAX_MISSED`.
+pub(crate) fn missed_slots(
+    config: &Config,
+    last_backup: DateTime<Utc>,
+    now: DateTime<Utc>,
+) -> Result<usize> {
+    let timing = Timing::new(config)?;
+    let mut due = 0;
+    let mut slot = last_backup;
+    while due <= MAX_MISSED {
+        let next = timing.due_after(slot)?;
+        // An interval of nothing is always due, so there is nothing to miss
+        if next > now || next <= slot {
+            break;
+        }
+        due += 1;
+        slot = next;
+    }
+    Ok(due.saturating_sub(1))
+}
+
+/// Work out how far a config has fallen behind at `now`, and log a warning if it has.
+pub(crate) fn behind(
+    name: &str,
+    config: &Config,
+    last_backup: &Option<CatalogEntry>,
+    now: DateTime<Utc>,
+) -> Result<Behind> {
+    let Some(last_backup) = last_backup else {
+        return Ok(Behind::default());
+    };
+    let missed = missed_slots(config, last_backup.created, now)?;
+    if missed > 0 {
+        warn!(
+            "{} missed {} scheduled backup(s) since its last backup at {}",
+            name, missed, last_backup.created
+        );
+    }
+    let mut overdue_since = None;
+    if let Some(alert_after) = &config.alert_after {
+        let alert_at = alert_after
+            .parse::<Interval>()?
+            .after(last_backup.created)
+            .ok_or_else(|| {
+                Error::Interval(alert_after.clone(), "it is out of range".to_string())
+            })?;
+        if alert_at < now {
+            warn!(
+                "{} has not been backed up since {}, which is longer than {}",
+                name, last_backup.created, alert_after
+            );
+            overdue_since = Some(last_backup.created);
+        }
+    }
+    Ok(Behind {
+        missed,
+        overdue_since,
+    })
+}
+
+/// When a config should next take a backup, at or after `now`: as soon as it is due, once its
+/// window is open.
+pub(crate) fn next_run(
+    config: &Config,
+    last_backup: &Option<CatalogEntry>,
+    now: DateTime<Utc>,
+) -> Result<DateTime<Utc>> {
+    let due = next_due(config, last_backup)?.map_or(now, |due| due.max(now));
+    let Some(window) = &config.window else {
+        return Ok(due);
+    };
+    Ok(window
+        .parse::<Window>()?
+        .next_open(&due.with_timezone(&Local))
+        .ok_or_else(|| Error::InvalidConfig(format!("the window {:?} never opens", window)))?
+        .with_timezone(&Utc))
+}
+
+/// Decide whether a config should skip taking a backup at `now`, returning why if so.
+fn skip(
+    config: &Config,
+    last_backup: &Option<CatalogEntry>,
+    now: DateTime<Utc>,
+    behind: &Behind,
+) -> Result<Option<Outcome>> {
+    if let (Some(last_backup), Some(due)) = (last_backup, next_due(config, last_backup)?) {
+        if due > now {
+            debug!("The next backup is due at {}", due);
+            return Ok(Some(Outcome::Skipped {
+                last_backup: last_backup.created,
+                behind: behind.clone(),
+            }));
+        }
+    }
+    if let Some(window) = &config.window {
+        if !window
+            .parse::<Window>()?
+            .contains(now.with_timezone(&Local).time())
+        {
+            return Ok(Some(Outcome::OutsideWindow {
+                window: window.clone(),
+                behind: behind.clone(),
+            }));
+        }
+    }
+    Ok(None)
+}
+
+pub fn sync_config(name: &str, config: &Config) -> Result<Outcome> {
     debug!("Syncing config {}: {:?}", name, config);
+    check_config(name, config)?;
 
-    // Check for existing backups, remotely if necessary
-    let raw_ls;
-    if let Some(host) = &config.host {
-        raw_ls = run(Command::new("ssh").args([host, "ls", "-At", "--full-time", &config.dir]));
-    } else {
-        raw_ls = run(Command::new("ls").args(["-At", "--full-time", &config.dir]))
+    // Only one sync at a time may take or prune backups in a dir, or of a section
+    let dir = Path::new(&config.dir);
+    let _locks = match Lock::acquire_both(&config.host, dir, name) {
+        Err(Error::Locked(_, holder)) => return Ok(Outcome::AlreadyRunning { holder }),
+        lock => lock?,
+    };
+
+    // The catalog is the source of truth for which backups exist
+    let catalog_file = Catalog::destination(&config.host, dir);
+    let (mut catalog, imported) = load_catalog(name, config)?;
+    if imported {
+        catalog.save(&catalog_file)?;
     }
-    let existing_files = parse_ls(&raw_ls);
 
-    // Skip this backup if the last backup was too recent
-    let last_backup =
-        find_most_recent_matching(&existing_files, &config.format).map(|(_, time)| time);
+    // Skip this backup if it isn't due yet, or can't run now
+    let last_backup = catalog
+        .backups(name)
+        .first()
+        .map(|backup| (*backup).clone());
     let now = Utc::now();
-    if let Some(last_backup) = last_backup {
-        debug!("Last backup was at {}", last_backup);
-        if last_backup > offset_by_interval(now, &config.interval) {
-            debug!("Skipping backup");
-            return Ok(None);
-        }
+    let behind = behind(name, config, &last_backup, now)?;
+    if let Some(skipped) = skip(config, &last_backup, now, &behind)? {
+        debug!("Skipping backup");
+        return Ok(skipped);
     }
 
+    // Clean up after any sync that was interrupted. This waits until a backup is due, so that
+    // skipping one never has to list the dir
+    remove_partials(config)?;
+
     let filename = format!("{}", now.format(&config.format));
-    let destination = Path::new(&config.dir).join(&filename);
-    // Use a temporary file if we are uploading, otherwise use the destination
-    let output = if config.host.is_some() {
-        Path::new("/tmp/").join(&filename)
+    let destination = dir.join(&filename);
+
+    // Run the backup, streaming it to the host if there is one
+    let repository = Repository::new(dir, &config.gpg_id);
+    let (written, parent) = if config.repository {
+        let (_, written) = repository.backup(&config.globs, &filename)?;
+        (written, None)
+    } else if let Some(incremental) = config.incremental {
+        let parent =
+            last_backup.and_then(|backup| incremental_parent(config, &backup.name, incremental));
+        let (_, written) = backup_with_snapshot(
+            &config.globs,
+            &Destination::new(&config.host, destination.clone()),
+            &config.gpg_id,
+            parent
+                .as_ref()
+                .map(|(name, snapshot)| (name.as_str(), snapshot)),
+        )?;
+        (written, parent.map(|(name, _)| name))
     } else {
-        destination.clone()
+        let written = backup_to(
+            &config.globs,
+            &Destination::new(&config.host, destination.clone()),
+            &config.gpg_id,
+        )?;
+        (written, None)
     };
+    catalog.add(CatalogEntry {
+        name: filename,
+        created: now,
+        config: name.to_string(),
+        globs: config.globs.clone(),
+        size: Some(written.size),
+        sha256: Some(written.sha256),
+        gpg_id: config.gpg_id.clone(),
+        parent,
+    });
+    catalog.save(&catalog_file)?;
 
-    // Run the backup
-    backup(&config.globs, &output, &config.gpg_id)?;
+    // Delete redundant copies if necessary
+    let mut pruned = vec![];
+    if let Some(redundant) = redundant(name, config, &catalog, now)? {
+        pruned = remove_backups(config, &mut catalog, &redundant)?;
+    }
+    if config.repository {
+        repository.collect_garbage()?;
+    }
+    Ok(Outcome::Created {
+        backup: destination,
+        pruned,
+        behind,
+    })
+}
 
-    // Copy the archive if there is a host specified
-    if let Some(host) = &config.host {
-        run(Command::new("scp").args([
-            "-O",
-            output.into_os_string().to_str().unwrap(),
-            &format!(
-                "{}:{}",
-                &host,
-                destination.clone().into_os_string().to_str().unwrap()
-            ),
-        ]));
+/// Remove some of a config's backups, along with their sidecars, and save the catalog without
+/// them. Returns the paths of the backups that were removed.
+pub(crate) fn remove_backups(
+    config: &Config,
+    catalog: &mut Catalog,
+    names: &[String],
+) -> Result<Vec<PathBuf>> {
+    let dir = Path::new(&config.dir);
+    let backups: Vec<PathBuf> = names.iter().map(|name| dir.join(name)).collect();
+    if backups.is_empty() {
+        return Ok(backups);
     }
+    debug!("Removing redundant backups {:?}", backups);
+    let mut sidecars = vec![];
+    if !config.repository {
+        sidecars.push(SHA256_SUFFIX);
+    }
+    if config.incremental.is_some() {
+        sidecars.push(SNAPSHOT_SUFFIX);
+    }
+    let mut files = backups.clone();
+    for suffix in sidecars.into_iter() {
+        files.extend(backups.iter().map(|file| {
+            let mut sidecar = file.clone().into_os_string();
+            sidecar.push(suffix);
+            PathBuf::from(sidecar)
+        }));
+    }
+    remove_files(config, &files)?;
+    catalog.remove(names);
+    catalog.save(&Catalog::destination(&config.host, dir))?;
+    Ok(backups)
+}
 
-    // Delete redundant copies if necessary
-    if let Some(copies) = config.copies {
-        let files_to_remove: Vec<String> = filter_backups(&existing_files, &config.format)
+/// Work out what syncing a config would do, without creating or removing anything.
+pub fn plan_config(name: &str, config: &Config) -> Result<Outcome> {
+    debug!("Planning config {}: {:?}", name, config);
+    check_config(name, config)?;
+    let (catalog, _) = load_catalog(name, config)?;
+    let last_backup = catalog
+        .backups(name)
+        .first()
+        .map(|backup| (*backup).clone());
+    let now = Utc::now();
+    let behind = behind(name, config, &last_backup, now)?;
+    if let Some(skipped) = skip(config, &last_backup, now, &behind)? {
+        return Ok(skipped);
+    }
+    let parent = match (&last_backup, config.incremental) {
+        (Some(last_backup), Some(incremental)) => {
+            incremental_parent(config, &last_backup.name, incremental).map(|(name, _)| name)
+        }
+        _ => None,
+    };
+    let dir = Path::new(&config.dir);
+    Ok(Outcome::Planned {
+        backup: dir.join(format!("{}", now.format(&config.format))),
+        files: resolve_globs(&config.globs)?,
+        parent,
+        pruned: pruned_by_next_backup(name, config, &catalog)?
             .iter()
-            .skip(copies - 1)
-            .map(|(name, _)| {
-                Path::new(&config.dir)
-                    .join(name)
-                    .into_os_string()
-                    .into_string()
-                    .unwrap()
-            })
-            .collect();
-        if !files_to_remove.is_empty() {
-            debug!("Removing redundant backups {:?}", files_to_remove);
-            if let Some(host) = &config.host {
-                run(Command::new("ssh")
-                    .args([host, "rm"])
-                    .args(&files_to_remove));
-            } else {
-                run(Command::new("rm").args(&files_to_remove));
+            .map(|name| dir.join(name))
+            .collect(),
+        behind,
+    })
+}
+
+/// Load the catalog of a config's dir. The first time the config has no backups in it, any it
+/// took before there was a catalog are added, and true is returned to say the catalog should be
+/// saved. The catalog records that this was done, so the dir is only listed once.
+pub(crate) fn load_catalog(name: &str, config: &Config) -> Result<(Catalog, bool)> {
+    let mut catalog = Catalog::load(&Catalog::destination(&config.host, Path::new(&config.dir)))?;
+    if catalog.imported.contains(name) || !catalog.backups(name).is_empty() {
+        return Ok((catalog, false));
+    }
+    import_backups(name, config, &mut catalog)?;
+    catalog.imported.insert(name.to_string());
+    Ok((catalog, true))
+}
+
+/// The backups that will be pruned once the config takes its next backup. If the next backup
+/// could be incremental, it is assumed to be, which keeps its chain.
+pub(crate) fn pruned_by_next_backup(
+    name: &str,
+    config: &Config,
+    catalog: &Catalog,
+) -> Result<Vec<String>> {
+    let mut catalog = catalog.clone();
+    let parent = match config.incremental {
+        Some(_) => catalog
+            .backups(name)
+            .first()
+            .map(|backup| backup.name.clone()),
+        None => None,
+    };
+    let now = Utc::now();
+    catalog.add(CatalogEntry {
+        name: String::new(),
+        created: now,
+        config: name.to_string(),
+        globs: config.globs.clone(),
+        size: None,
+        sha256: None,
+        gpg_id: config.gpg_id.clone(),
+        parent,
+    });
+    Ok(redundant(name, config, &catalog, now)?.unwrap_or_default())
+}
+
+/// Add the backups a config took before its directory had a catalog. They are found by listing
+/// the directory, remotely if necessary, and looking for files named by the config's format.
+fn import_backups(name: &str, config: &Config, catalog: &mut Catalog) -> Result<()> {
+    for (file, time) in filter_backups(&list_dir(config)?, &config.format) {
+        debug!("Adding {} to the catalog", file);
+        let parent = if config.incremental.is_some() {
+            let backup = Destination::new(&config.host, Path::new(&config.dir).join(&file));
+            match Snapshot::load(&backup, &config.gpg_id) {
+                Ok(snapshot) => snapshot.and_then(|snapshot| snapshot.parent),
+                Err(err) => {
+                    warn!("Failed to load the snapshot of {}: {}", file, err);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        catalog.add(CatalogEntry {
+            name: file,
+            created: time,
+            config: name.to_string(),
+            globs: config.globs.clone(),
+            size: None,
+            sha256: None,
+            gpg_id: config.gpg_id.clone(),
+            parent,
+        });
+    }
+    Ok(())
+}
+
+/// List the files in a config's dir, remotely if necessary, newest first.
+fn list_dir(config: &Config) -> Result<Vec<(String, DateTime<Utc>)>> {
+    let raw_ls = if let Some(host) = &config.host {
+        run(Command::new("ssh").args([host, "ls", "-At", "--full-time", &config.dir]))
+            .map_err(Error::remote(host))?
+    } else {
+        run(Command::new("ls").args(["-At", "--full-time", &config.dir]))?
+    };
+    Ok(parse_ls(&raw_ls))
+}
+
+/// Remove files from a config's dir, remotely if necessary.
+fn remove_files(config: &Config, files: &[PathBuf]) -> Result<()> {
+    if let Some(host) = &config.host {
+        run(Command::new("ssh").args([host, "rm", "-f"]).args(files))
+            .map_err(Error::remote(host))?;
+    } else {
+        run(Command::new("rm").arg("-f").args(files))?;
+    }
+    Ok(())
+}
+
+/// Find the files a config left behind when it was interrupted while writing a backup or one of
+/// its sidecars, by looking for partial files named by the config's format. A backup can take
+/// any amount of time before it is interrupted, so unlike `time_matches` this doesn't care when
+/// the files were last modified.
+fn find_partials(files: &[(String, DateTime<Utc>)], format: &str) -> Vec<String> {
+    files
+        .iter()
+        .filter_map(|(file, _)| {
+            let name = file.strip_suffix(PARTIAL_SUFFIX)?;
+            let name = [SHA256_SUFFIX, SNAPSHOT_SUFFIX]
+                .iter()
+                .find_map(|suffix| name.strip_suffix(suffix))
+                .unwrap_or(name);
+            let mut parsed = Parsed::new();
+            format::parse(&mut parsed, name, StrftimeItems::new(format))
+                .is_ok()
+                .then(|| file.clone())
+        })
+        .collect()
+}
+
+/// Remove any partial files left behind by a sync of this config that was interrupted.
+fn remove_partials(config: &Config) -> Result<()> {
+    let partials: Vec<PathBuf> = find_partials(&list_dir(config)?, &config.format)
+        .iter()
+        .map(|file| Path::new(&config.dir).join(file))
+        .collect();
+    if !partials.is_empty() {
+        warn!(
+            "Removing partial files left behind by an interrupted sync: {:?}",
+            partials
+        );
+        remove_files(config, &partials)?;
+    }
+    Ok(())
+}
+
+/// Find the snapshot of the last backup if the next backup can be an increment on it.
+fn incremental_parent(
+    config: &Config,
+    name: &str,
+    incremental: usize,
+) -> Option<(String, Snapshot)> {
+    let backup = Destination::new(&config.host, Path::new(&config.dir).join(name));
+    match Snapshot::load(&backup, &config.gpg_id) {
+        Ok(Some(snapshot)) if snapshot.depth < incremental => Some((name.to_string(), snapshot)),
+        Ok(Some(_)) => {
+            debug!(
+                "Taking a full backup after {} incremental backups",
+                incremental
+            );
+            None
+        }
+        Ok(None) => {
+            warn!("{} has no snapshot, taking a full backup", name);
+            None
+        }
+        Err(err) => {
+            warn!(
+                "Failed to load the snapshot of {}, taking a full backup: {}",
+                name, err
+            );
+            None
+        }
+    }
+}
+
+/// Print a line for every config describing what happened to it.
+fn print_summary(results: &[(&String, Result<Outcome>)]) {
+    let width = results
+        .iter()
+        .map(|(name, _)| name.len())
+        .max()
+        .unwrap_or(0);
+    for (name, result) in results.iter() {
+        match result {
+            Ok(outcome) => println!("{:width$}  {}", name, outcome),
+            Err(err) => println!("{:width$}  failed: {}", name, err),
+        }
+        if let Ok(Outcome::Planned { files, pruned, .. }) = result {
+            for file in files.iter() {
+                println!("{:width$}    archive {}", "", file.display());
+            }
+            for backup in pruned.iter() {
+                println!("{:width$}    prune {}", "", backup.display());
             }
         }
     }
-    Ok(Some(destination))
 }
 
-pub fn sync(file: &Path) -> Result<(), Error> {
+/// Sync every config in a file. A failure in one config does not prevent the others from being
+/// synced, but is reported once they are all done. A dry run only reports what would be done.
+pub fn sync(file: &Path, dry_run: bool) -> Result<()> {
     debug!("Syncing file {:?}", file);
-    let configs = read_config_file(file);
-    for (name, config) in configs.configs.iter() {
-        sync_config(name, config)?;
+    let configs = read_config_file(file)?;
+    let names = configs.names(&None)?;
+    let results: Vec<(&String, Result<Outcome>)> = names
+        .into_iter()
+        .map(|name| {
+            let result = if dry_run {
+                plan_config(name, &configs.configs[name])
+            } else {
+                sync_config(name, &configs.configs[name])
+            };
+            if let Err(err) = &result {
+                error!("Failed to sync {}: {}", name, err);
+            }
+            (name, result)
+        })
+        .collect();
+    print_summary(&results);
+    let failed = results.iter().filter(|(_, result)| result.is_err()).count();
+    if failed > 0 {
+        return Err(Error::Sync {
+            failed,
+            total: results.len(),
+        });
     }
     Ok(())
 }
 
 #[cfg(test)]
 mod test {
-    use chrono::{TimeZone, Utc};
+    use chrono::TimeZone;
 
     use super::*;
 
+    fn backup_at(created: DateTime<Utc>) -> Option<CatalogEntry> {
+        Some(CatalogEntry {
+            name: "backup".to_string(),
+            created,
+            config: "config".to_string(),
+            globs: vec![],
+            size: None,
+            sha256: None,
+            gpg_id: None,
+            parent: None,
+        })
+    }
+
     #[test]
-    fn test_offset_by_interval_month() {
-        let now = Utc.with_ymd_and_hms(2000, 7, 1, 0, 0, 0).unwrap();
-        assert_eq!(
-            offset_by_interval(now, "0 M"),
-            Utc.with_ymd_and_hms(2000, 7, 1, 0, 0, 0).unwrap(),
-        );
+    fn test_next_due() {
+        let last = Utc.with_ymd_and_hms(2000, 7, 1, 12, 0, 0).unwrap();
+        let interval = Config {
+            interval: "1 day".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(next_due(&interval, &None).unwrap(), None);
         assert_eq!(
-            offset_by_interval(now, "1M"),
-            Utc.with_ymd_and_hms(2000, 6, 1, 0, 0, 0).unwrap(),
+            next_due(&interval, &backup_at(last)).unwrap(),
+            Some(Utc.with_ymd_and_hms(2000, 7, 2, 12, 0, 0).unwrap())
         );
+        // The next slot after the last backup, in local time
+        let schedule = Config {
+            schedule: Some("0 3 * * *".to_string()),
+            ..Default::default()
+        };
+        let slot = Local
+            .with_ymd_and_hms(2000, 7, 2, 3, 0, 0)
+            .unwrap()
+            .with_timezone(&Utc);
+        let last = Local
+            .with_ymd_and_hms(2000, 7, 1, 12, 0, 0)
+            .unwrap()
+            .with_timezone(&Utc);
+        assert_eq!(next_due(&schedule, &backup_at(last)).unwrap(), Some(slot));
+        // With both, whichever is later
+        let both = Config {
+            interval: "1 day".to_string(),
+            ..schedule
+        };
         assert_eq!(
-            offset_by_interval(now, "6month"),
-            Utc.with_ymd_and_hms(2000, 1, 1, 0, 0, 0).unwrap(),
-        );
-        assert_eq!(
-            offset_by_interval(now, "10 months"),
-            Utc.with_ymd_and_hms(1999, 9, 1, 0, 0, 0).unwrap(),
+            next_due(&both, &backup_at(last)).unwrap(),
+            Some(slot + Duration::days(1))
         );
     }
 
     #[test]
-    fn test_offset_by_interval_week() {
-        let now = Utc.with_ymd_and_hms(2000, 7, 28, 0, 0, 0).unwrap();
+    fn test_skip() {
+        let now = Utc::now();
+        let local = now.with_timezone(&Local);
+        let config = Config {
+            interval: "1 hour".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(skip(&config, &None, now, &Behind::default()).unwrap(), None);
         assert_eq!(
-            offset_by_interval(now, "0 w"),
-            Utc.with_ymd_and_hms(2000, 7, 28, 0, 0, 0).unwrap(),
+            skip(
+                &config,
+                &backup_at(now - Duration::minutes(30)),
+                now,
+                &Behind::default()
+            )
+            .unwrap(),
+            Some(Outcome::Skipped {
+                last_backup: now - Duration::minutes(30),
+                behind: Behind::default(),
+            })
         );
-        assert_eq!(
-            offset_by_interval(now, "1w"),
-            Utc.with_ymd_and_hms(2000, 7, 21, 0, 0, 0).unwrap(),
+        // A window that closed an hour ago
+        let window = format!(
+            "{}-{}",
+            (local - Duration::hours(2)).format("%H:%M"),
+            (local - Duration::hours(1)).format("%H:%M")
         );
+        let config = Config {
+            window: Some(window.clone()),
+            ..config
+        };
         assert_eq!(
-            offset_by_interval(now, "2week"),
-            Utc.with_ymd_and_hms(2000, 7, 14, 0, 0, 0).unwrap(),
-        );
-        assert_eq!(
-            offset_by_interval(now, "4 weeks"),
-            Utc.with_ymd_and_hms(2000, 6, 30, 0, 0, 0).unwrap(),
+            skip(&config, &None, now, &Behind::default()).unwrap(),
+            Some(Outcome::OutsideWindow {
+                window,
+                behind: Behind::default(),
+            })
         );
+        let config = Config {
+            window: Some(format!(
+                "{}-{}",
+                (local - Duration::hours(1)).format("%H:%M"),
+                (local + Duration::hours(1)).format("%H:%M")
+            )),
+            ..config
+        };
+        assert_eq!(skip(&config, &None, now, &Behind::default()).unwrap(), None);
     }
 
     #[test]
-    fn test_offset_by_interval_day() {
-        let now = Utc.with_ymd_and_hms(2000, 7, 30, 0, 0, 0).unwrap();
-        assert_eq!(
-            offset_by_interval(now, "0 d"),
-            Utc.with_ymd_and_hms(2000, 7, 30, 0, 0, 0).unwrap(),
-        );
-        assert_eq!(
-            offset_by_interval(now, "1d"),
-            Utc.with_ymd_and_hms(2000, 7, 29, 0, 0, 0).unwrap(),
-        );
+    fn test_next_run() {
+        let now = Utc::now();
+        let config = Config {
+            interval: "1 hour".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(next_run(&config, &None, now).unwrap(), now);
+        let last = now - Duration::minutes(30);
         assert_eq!(
-            offset_by_interval(now, "2day"),
-            Utc.with_ymd_and_hms(2000, 7, 28, 0, 0, 0).unwrap(),
+            next_run(&config, &backup_at(last), now).unwrap(),
+            last + Duration::hours(1)
         );
+        // Overdue backups run now rather than in the past
+        let last = now - Duration::hours(2);
+        assert_eq!(next_run(&config, &backup_at(last), now).unwrap(), now);
+        // Once the window opens, if it isn't already
+        let opens = (now + Duration::hours(2)).with_timezone(&Local);
+        let config = Config {
+            window: Some(format!(
+                "{}-{}",
+                opens.format("%H:%M"),
+                (opens + Duration::hours(1)).format("%H:%M")
+            )),
+            ..config
+        };
+        let run = next_run(&config, &None, now).unwrap();
         assert_eq!(
-            offset_by_interval(now, "30 days"),
-            Utc.with_ymd_and_hms(2000, 6, 30, 0, 0, 0).unwrap(),
+            run.with_timezone(&Local).format("%H:%M").to_string(),
+            opens.format("%H:%M").to_string()
         );
+        assert!(run > now && run <= now + Duration::hours(2));
     }
 
     #[test]
-    fn test_offset_by_interval_hour() {
-        let now = Utc.with_ymd_and_hms(2000, 7, 3, 0, 0, 0).unwrap();
-        assert_eq!(
-            offset_by_interval(now, "0 h"),
-            Utc.with_ymd_and_hms(2000, 7, 3, 0, 0, 0).unwrap(),
-        );
-        assert_eq!(
-            offset_by_interval(now, "1h"),
-            Utc.with_ymd_and_hms(2000, 7, 2, 23, 0, 0).unwrap(),
-        );
-        assert_eq!(
-            offset_by_interval(now, "24hour"),
-            Utc.with_ymd_and_hms(2000, 7, 2, 0, 0, 0).unwrap(),
-        );
-        assert_eq!(
-            offset_by_interval(now, "30 hours"),
-            Utc.with_ymd_and_hms(2000, 7, 1, 18, 0, 0).unwrap(),
-        );
+    fn test_missed_slots() {
+        let last = Utc.with_ymd_and_hms(2000, 7, 1, 12, 0, 0).unwrap();
+        let hourly = Config {
+            interval: "1 hour".to_string(),
+            ..Default::default()
+        };
+        let missed = |config: &Config, after| missed_slots(config, last, last + after).unwrap();
+        assert_eq!(missed(&hourly, Duration::minutes(30)), 0);
+        assert_eq!(missed(&hourly, Duration::minutes(90)), 0);
+        // Due at 13:00, 14:00, 15:00, 16:00 and 17:00, and the backup now makes up for 17:00
+        assert_eq!(missed(&hourly, Duration::minutes(330)), 4);
+        assert_eq!(missed(&hourly, Duration::days(10_000)), MAX_MISSED);
+        let always = Config {
+            interval: "0 seconds".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(missed(&always, Duration::days(1)), 0);
+        let daily = Config {
+            interval: String::new(),
+            schedule: Some("0 3 * * *".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(missed(&daily, Duration::days(7)), 6);
     }
 
     #[test]
-    fn test_offset_by_interval_minute() {
-        let now = Utc.with_ymd_and_hms(2000, 7, 2, 0, 0, 0).unwrap();
+    fn test_behind() {
+        let now = Utc::now();
+        let config = Config {
+            interval: "1 day".to_string(),
+            alert_after: Some("2 days".to_string()),
+            ..Default::default()
+        };
         assert_eq!(
-            offset_by_interval(now, "0 m"),
-            Utc.with_ymd_and_hms(2000, 7, 2, 0, 0, 0).unwrap(),
+            behind("config", &config, &None, now).unwrap(),
+            Behind::default()
         );
+        let last = now - Duration::days(1) - Duration::hours(1);
         assert_eq!(
-            offset_by_interval(now, "1m"),
-            Utc.with_ymd_and_hms(2000, 7, 1, 23, 59, 0).unwrap(),
+            behind("config", &config, &backup_at(last), now).unwrap(),
+            Behind::default()
         );
+        let last = now - Duration::days(3) - Duration::hours(1);
+        let behind = behind("config", &config, &backup_at(last), now).unwrap();
         assert_eq!(
-            offset_by_interval(now, "30minute"),
-            Utc.with_ymd_and_hms(2000, 7, 1, 23, 30, 0).unwrap(),
+            behind,
+            Behind {
+                missed: 2,
+                overdue_since: Some(last),
+            }
         );
         assert_eq!(
-            offset_by_interval(now, "90 minutes"),
-            Utc.with_ymd_and_hms(2000, 7, 1, 22, 30, 0).unwrap(),
+            behind.to_string(),
+            format!(
+                ", missed 2 scheduled backup(s), last backup at {} is older than alert_after",
+                last
+            )
         );
     }
 
     #[test]
-    fn test_offset_by_interval_second() {
-        let now = Utc.with_ymd_and_hms(2000, 7, 2, 0, 0, 0).unwrap();
-        assert_eq!(
-            offset_by_interval(now, "0 s"),
-            Utc.with_ymd_and_hms(2000, 7, 2, 0, 0, 0).unwrap(),
-        );
-        assert_eq!(
-            offset_by_interval(now, "1s"),
-            Utc.with_ymd_and_hms(2000, 7, 1, 23, 59, 59).unwrap(),
-        );
-        assert_eq!(
-            offset_by_interval(now, "30second"),
-            Utc.with_ymd_and_hms(2000, 7, 1, 23, 59, 30).unwrap(),
-        );
-        assert_eq!(
-            offset_by_interval(now, "90 seconds"),
-            Utc.with_ymd_and_hms(2000, 7, 1, 23, 58, 30).unwrap(),
-        );
+    fn test_check_config_schedule() {
+        let config = Config {
+            interval: String::new(),
+            ..Default::default()
+        };
+        assert!(matches!(
+            check_config("config", &config),
+            Err(Error::InvalidConfig(_))
+        ));
+        let config = Config {
+            schedule: Some("0 3 * *".to_string()),
+            ..config
+        };
+        assert!(matches!(
+            check_config("config", &config),
+            Err(Error::InvalidConfig(_))
+        ));
+        let config = Config {
+            schedule: Some("0 3 * * *".to_string()),
+            window: Some("01:00-05:00".to_string()),
+            ..config
+        };
+        assert!(check_config("config", &config).is_ok());
     }
 
     #[test]
@@ -340,7 +1037,32 @@ drwx------ 1 daniel users 30 2023-03-25 21:51:43.614781911 -0400 .ssh
     }
 
     #[test]
-    fn test_find_most_recent_matching() {
+    fn test_find_partials() {
+        // Long after the backups were started, however long they took
+        let time = Utc.with_ymd_and_hms(2000, 3, 4, 0, 0, 0).unwrap();
+        let files: Vec<(String, DateTime<Utc>)> = [
+            "a-02",
+            "a-02.partial",
+            "a-02.sha256.partial",
+            "a-02.snapshot.partial",
+            "b-02.partial",
+            "catalog.json.partial",
+        ]
+        .iter()
+        .map(|file| (file.to_string(), time))
+        .collect();
+        assert_eq!(
+            find_partials(&files, "a-%d"),
+            vec![
+                "a-02.partial".to_string(),
+                "a-02.sha256.partial".to_string(),
+                "a-02.snapshot.partial".to_string()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_filter_backups() {
         let files = [
             (
                 "a-01".to_string(),
@@ -355,14 +1077,37 @@ drwx------ 1 daniel users 30 2023-03-25 21:51:43.614781911 -0400 .ssh
                 Utc.with_ymd_and_hms(2000, 1, 3, 0, 0, 0).unwrap(),
             ),
         ];
+        assert_eq!(filter_backups(&files, "a-%d"), vec![files[0].clone()]);
         assert_eq!(
-            find_most_recent_matching(&files, "a-%d"),
-            Some(files[0].clone())
-        );
-        assert_eq!(
-            find_most_recent_matching(&files, "b-%d"),
-            Some(files[1].clone())
+            filter_backups(&files, "b-%d"),
+            vec![files[1].clone(), files[2].clone()]
         );
-        assert_eq!(find_most_recent_matching(&files, "c-%d"), None);
+        assert_eq!(filter_backups(&files, "c-%d"), vec![]);
+    }
+
+    #[test]
+    fn test_load_catalog_imports_once() {
+        let dir = std::env::temp_dir().join("backer-upper-sync-import");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = Config {
+            dir: dir.to_string_lossy().into_owned(),
+            format: "import_%Y-%m-%d_%H:%M:%S.tar.gz".to_string(),
+            ..Default::default()
+        };
+        let (catalog, imported) = load_catalog("import", &config).unwrap();
+        assert!(imported);
+        assert!(catalog.backups("import").is_empty());
+        catalog.save(&Catalog::destination(&None, &dir)).unwrap();
+        // A file that looks like an old backup is only found by the first import
+        let old = format!("{}", Utc::now().format(&config.format));
+        std::fs::write(dir.join(old), "").unwrap();
+        let (catalog, imported) = load_catalog("import", &config).unwrap();
+        assert!(!imported);
+        assert!(catalog.backups("import").is_empty());
+        // Other configs sharing the dir are still imported the first time
+        let (catalog, imported) = load_catalog("other", &config).unwrap();
+        assert!(imported);
+        assert_eq!(catalog.backups("other").len(), 1);
     }
 }
@@ -0,0 +1,143 @@
+use std::path::{Path, PathBuf};
+
+use log::{debug, error};
+
+use crate::archive::hash_archive;
+use crate::catalog::CatalogEntry;
+use crate::checksum::read_checksum;
+use crate::commands::restore::{open_backup, Opened};
+use crate::commands::sync::load_catalog;
+use crate::config::{read_config_file, Config};
+use crate::error::{Error, Result};
+use crate::repository::Repository;
+use crate::snapshot::Snapshot;
+use crate::stream::Destination;
+
+/// Check that a backup is stored exactly as the catalog says it was written.
+fn verify_stored(backup: &Destination, entry: &CatalogEntry) -> Result<()> {
+    let (Some(size), Some(sha256)) = (entry.size, &entry.sha256) else {
+        debug!("No checksum for {:?} in the catalog", backup.path());
+        return Ok(());
+    };
+    let stored = backup.measure()?;
+    if stored.size != size {
+        return Err(Error::Corrupt(
+            backup.path().to_path_buf(),
+            format!("expected {} bytes, found {}", size, stored.size),
+        ));
+    }
+    verify_sha256(backup, sha256, &stored.sha256)
+}
+
+fn verify_sha256(backup: &Destination, expected: &str, stored: &str) -> Result<()> {
+    if expected != stored {
+        return Err(Error::Corrupt(
+            backup.path().to_path_buf(),
+            format!("expected SHA-256 {}, found {}", expected, stored),
+        ));
+    }
+    Ok(())
+}
+
+/// Check that a backup decrypts and can be read all the way through. The files in an archive
+/// are checked against the hashes in its snapshot, and the chunks of a manifest against their
+/// names.
+pub fn verify_backup(
+    backup: &Destination,
+    gpg_id: &Option<String>,
+    snapshot: Option<&Snapshot>,
+) -> Result<()> {
+    let corrupt = |err: Error| match err {
+        Error::Io(e) => Error::Corrupt(backup.path().to_path_buf(), e.to_string()),
+        err => err,
+    };
+    let opened = open_backup(backup, gpg_id, |reader| hash_archive(reader)).map_err(corrupt)?;
+    match opened {
+        Opened::Archive(hashes) => {
+            let Some(snapshot) = snapshot else {
+                return Ok(());
+            };
+            for (path, hash) in hashes.iter() {
+                // Snapshots record absolute paths
+                let expected = snapshot
+                    .files
+                    .get(&Path::new("/").join(path))
+                    .and_then(|state| state.hash.as_ref());
+                if expected.is_some_and(|expected| expected != hash) {
+                    return Err(Error::Corrupt(
+                        backup.path().to_path_buf(),
+                        format!("{} does not match its snapshot", path.display()),
+                    ));
+                }
+            }
+        }
+        Opened::Manifest(manifest) => {
+            let repository = Repository::of_manifest(backup.path(), gpg_id);
+            for hash in manifest.chunks().into_iter() {
+                repository.load_chunk(hash)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Verify every backup of a config, returning the result for each one.
+pub fn verify_config(name: &str, config: &Config) -> Result<Vec<(PathBuf, Result<()>)>> {
+    let (catalog, _) = load_catalog(name, config)?;
+    let dir = Path::new(&config.dir);
+    Ok(catalog
+        .backups(name)
+        .into_iter()
+        .map(|entry| {
+            let path = dir.join(&entry.name);
+            let backup = Destination::new(&config.host, path.clone());
+            let verified = verify_stored(&backup, entry).and_then(|_| {
+                let snapshot = match config.incremental {
+                    Some(_) => Snapshot::load(&backup, &entry.gpg_id)?,
+                    None => None,
+                };
+                verify_backup(&backup, &entry.gpg_id, snapshot.as_ref())
+            });
+            (path, verified)
+        })
+        .collect())
+}
+
+/// Verify a single backup, and its checksum if it has one.
+pub fn verify(backup: &Path, gpg_id: &Option<String>) -> Result<()> {
+    let backup = Destination::Local(backup.to_path_buf());
+    if let Some(sha256) = read_checksum(&backup)? {
+        verify_sha256(&backup, &sha256, &backup.measure()?.sha256)?;
+    }
+    let snapshot = Snapshot::load(&backup, gpg_id)?;
+    verify_backup(&backup, gpg_id, snapshot.as_ref())?;
+    println!("{}  ok", backup.path().display());
+    Ok(())
+}
+
+/// Verify every backup of every config in a file, or just those of the one named `section`.
+pub fn verify_file(file: &Path, section: &Option<String>) -> Result<()> {
+    let configs = read_config_file(file)?;
+    let names = configs.names(section)?;
+    let mut results = vec![];
+    for name in names.into_iter() {
+        results.extend(verify_config(name, &configs.configs[name])?);
+    }
+    for (backup, result) in results.iter() {
+        match result {
+            Ok(()) => println!("{}  ok", backup.display()),
+            Err(err) => {
+                error!("Failed to verify {:?}: {}", backup, err);
+                println!("{}  failed: {}", backup.display(), err);
+            }
+        }
+    }
+    let failed = results.iter().filter(|(_, result)| result.is_err()).count();
+    if failed > 0 {
+        return Err(Error::Verify {
+            failed,
+            total: results.len(),
+        });
+    }
+    Ok(())
+}
@@ -3,6 +3,9 @@ use std::path::Path;
 
 use serde::{Deserialize, Serialize};
 
+use crate::error::{Error, Result};
+use crate::retention::Retention;
+
 /// A configuration for a single backup. A config file can have multiple Configs.
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct Config {
@@ -11,8 +14,25 @@ pub struct Config {
     pub host: Option<String>,
     pub dir: String,
     pub format: String,
+    /// How long to wait after a backup before taking the next one. Can be left out if there is
+    /// a schedule.
+    #[serde(default)]
     pub interval: String,
+    /// Only take backups at or after the times of this cron expression, like "0 3 * * *".
+    pub schedule: Option<String>,
+    /// Only take backups during this time of day, like "01:00-05:00".
+    pub window: Option<String>,
+    /// Warn when the last backup is older than this, like "2 days".
+    pub alert_after: Option<String>,
     pub copies: Option<usize>,
+    /// Take up to this many incremental backups after each full backup.
+    pub incremental: Option<usize>,
+    /// Store backups as manifests of deduplicated chunks instead of as archives.
+    #[serde(default)]
+    pub repository: bool,
+    /// Keep backups on several timescales, in addition to the newest `copies`.
+    #[serde(default, skip_serializing_if = "Retention::is_empty")]
+    pub retention: Retention,
 }
 
 /// A collection of Configs. This is the format used for saving configs to a file.
@@ -33,14 +53,33 @@ impl ConfigCollection {
         config_collection.configs.insert(name.to_string(), config);
         config_collection
     }
+
+    /// The names of every config in sorted order, or just `section` if it is given.
+    pub fn names(&self, section: &Option<String>) -> Result<Vec<&String>> {
+        match section {
+            Some(section) => match self.configs.get_key_value(section) {
+                Some((name, _)) => Ok(vec![name]),
+                None => Err(Error::InvalidConfig(format!(
+                    "there is no section named {}",
+                    section
+                ))),
+            },
+            None => {
+                let mut names: Vec<&String> = self.configs.keys().collect();
+                names.sort();
+                Ok(names)
+            }
+        }
+    }
 }
 
-pub fn read_config_file(file: &Path) -> ConfigCollection {
-    let contents = std::fs::read_to_string(file).expect("error reading config file");
-    toml::from_str(&contents).expect("error deserializing config file")
+pub fn read_config_file(file: &Path) -> Result<ConfigCollection> {
+    let contents = std::fs::read_to_string(file)?;
+    Ok(toml::from_str(&contents)?)
 }
 
-pub fn write_config_file(config: &ConfigCollection, file: &Path) {
-    let contents = toml::to_string(config).expect("error serializing config file");
-    std::fs::write(file, contents).expect("error writing config file");
+pub fn write_config_file(config: &ConfigCollection, file: &Path) -> Result<()> {
+    let contents = toml::to_string(config)?;
+    std::fs::write(file, contents)?;
+    Ok(())
 }
@@ -0,0 +1,112 @@
+use std::fmt;
+use std::path::PathBuf;
+use std::process::Command;
+
+use crate::health::State;
+use crate::lock::Holder;
+
+/// The failure of an external command, along with whatever it printed to stderr.
+#[derive(Debug)]
+pub struct CommandError {
+    pub command: String,
+    pub code: Option<i32>,
+    pub stderr: String,
+}
+
+impl CommandError {
+    pub fn new(command: &Command, code: Option<i32>, stderr: &str) -> CommandError {
+        CommandError {
+            command: format!("{:?}", command),
+            code,
+            stderr: stderr.trim().to_string(),
+        }
+    }
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.code {
+            Some(code) => write!(f, "{} exited with code {}", self.command, code)?,
+            None => write!(f, "{} failed", self.command)?,
+        }
+        if !self.stderr.is_empty() {
+            write!(f, ": {}", self.stderr)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+/// Everything that can go wrong while backing up, restoring or syncing.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("invalid glob: {0}")]
+    Pattern(#[from] glob::PatternError),
+    #[error("error reading glob match: {0}")]
+    Glob(#[from] glob::GlobError),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Command(#[from] CommandError),
+    #[error("error parsing config file: {0}")]
+    Config(#[from] toml::de::Error),
+    #[error("error serializing config file: {0}")]
+    ConfigSerialize(#[from] toml::ser::Error),
+    #[error("invalid config: {0}")]
+    InvalidConfig(String),
+    #[error("invalid interval {0:?}: {1}")]
+    Interval(String, String),
+    #[error("gpg failed: {0}")]
+    Gpg(CommandError),
+    #[error("error communicating with {host}: {source}")]
+    Remote { host: String, source: CommandError },
+    #[error("error reading backup metadata: {0}")]
+    Metadata(#[from] serde_json::Error),
+    #[error("{} is corrupt: {1}", .0.display())]
+    Corrupt(PathBuf, String),
+    #[error("{failed} of {total} config(s) failed to sync")]
+    Sync { failed: usize, total: usize },
+    #[error("{failed} of {total} backup(s) failed verification")]
+    Verify { failed: usize, total: usize },
+    #[error("{failed} of {total} config(s) failed to prune")]
+    Prune { failed: usize, total: usize },
+    #[error("{} is held by {1}", .0.display())]
+    Locked(PathBuf, Holder),
+    #[error("backups are {0}")]
+    Status(State),
+}
+
+impl Error {
+    /// The exit code the CLI should use when it fails with this error.
+    ///
+    /// 2 is used by clap for usage errors, and a panic exits with 101 like in any Rust program.
+    /// 0 to 3 are left to `status`, which exits with the codes of Nagios checks (see
+    /// `Cli::exit_code`), so no other error uses them.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Io(_) => 4,
+            Error::Command(_) => 5,
+            Error::Config(_) | Error::ConfigSerialize(_) | Error::InvalidConfig(_) => 6,
+            Error::Interval(..) => 7,
+            Error::Gpg(_) => 8,
+            Error::Remote { .. } => 9,
+            Error::Sync { .. } => 10,
+            Error::Metadata(_) => 11,
+            Error::Corrupt(_, _) => 12,
+            Error::Verify { .. } => 13,
+            Error::Locked(..) => 14,
+            Error::Pattern(_) | Error::Glob(_) => 15,
+            Error::Prune { .. } => 16,
+            Error::Status(state) => state.exit_code(),
+        }
+    }
+
+    /// Wrap a failed ssh command.
+    pub fn remote(host: &str) -> impl FnOnce(CommandError) -> Error + Send + 'static {
+        let host = host.to_string();
+        move |source| Error::Remote { host, source }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
@@ -0,0 +1,51 @@
+//! How healthy backups are, shared by the `status` command and the exit codes it uses.
+
+use std::fmt;
+
+/// How healthy backups are, from best to worst, in the style of Nagios checks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum State {
+    Ok,
+    Warn,
+    /// The health of the backups couldn't be checked.
+    Unknown,
+    Critical,
+}
+
+impl State {
+    /// The exit code a Nagios or Icinga check uses for this state.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            State::Ok => 0,
+            State::Warn => 1,
+            State::Critical => 2,
+            State::Unknown => 3,
+        }
+    }
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            State::Ok => "OK",
+            State::Warn => "WARN",
+            State::Unknown => "UNKNOWN",
+            State::Critical => "CRITICAL",
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_state() {
+        assert!(State::Ok < State::Warn && State::Unknown < State::Critical);
+        assert_eq!(
+            [State::Ok, State::Warn, State::Critical, State::Unknown]
+                .map(|state| state.exit_code()),
+            [0, 1, 2, 3]
+        );
+    }
+}
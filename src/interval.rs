@@ -0,0 +1,399 @@
+//! Intervals like "1 day 12 hours" or "P1DT12H", used for schedules and retention.
+
+use std::str::FromStr;
+
+use chrono::{DateTime, Days, Duration, Months, TimeZone};
+use log::debug;
+use regex::Regex;
+
+use crate::error::{Error, Result};
+
+/// A length of time. Months (and years) and days are calendar units, so how long they are
+/// depends on when they are counted from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Interval {
+    months: u32,
+    days: u64,
+    duration: Duration,
+}
+
+impl Default for Interval {
+    fn default() -> Interval {
+        Interval {
+            months: 0,
+            days: 0,
+            duration: Duration::zero(),
+        }
+    }
+}
+
+impl Interval {
+    /// Add `count` of `unit` to the interval.
+    fn add(&mut self, count: u64, unit: &str) -> std::result::Result<(), String> {
+        let too_large = || format!("{} {} is too large", count, unit);
+        let small = u32::try_from(count).map_err(|_| too_large())?;
+        match unit {
+            "y" | "Y" | "year" | "years" => {
+                let months = small.checked_mul(12).ok_or_else(too_large)?;
+                self.months = self.months.checked_add(months).ok_or_else(too_large)?;
+            }
+            "M" | "month" | "months" => {
+                self.months = self.months.checked_add(small).ok_or_else(too_large)?;
+            }
+            "d" | "D" | "day" | "days" => {
+                self.days = self.days.checked_add(count).ok_or_else(too_large)?;
+            }
+            unit => {
+                let duration = match unit {
+                    "w" | "W" | "week" | "weeks" => Duration::try_weeks(small.into()),
+                    "h" | "H" | "hour" | "hours" => Duration::try_hours(small.into()),
+                    "m" | "min" | "mins" | "minute" | "minutes" => {
+                        Duration::try_minutes(small.into())
+                    }
+                    "s" | "S" | "sec" | "secs" | "second" | "seconds" => {
+                        Duration::try_seconds(small.into())
+                    }
+                    unit => return Err(format!("unknown unit {:?}", unit)),
+                };
+                self.duration = duration
+                    .and_then(|duration| self.duration.checked_add(&duration))
+                    .ok_or_else(too_large)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Parse an ISO 8601 duration like "P1Y2M3DT4H5M6S".
+    fn parse_iso(interval: &str) -> std::result::Result<Interval, String> {
+        let pattern = Regex::new(
+            r"^P(?:([0-9]+)Y)?(?:([0-9]+)M)?(?:([0-9]+)W)?(?:([0-9]+)D)?(?:T(?:([0-9]+)H)?(?:([0-9]+)M)?(?:([0-9]+)S)?)?$",
+        )
+        .unwrap();
+        let captures = pattern
+            .captures(interval)
+            .filter(|_| interval != "P" && !interval.ends_with('T'))
+            .ok_or("it is not a valid ISO 8601 duration")?;
+        let mut parsed = Interval::default();
+        for (group, unit) in ["Y", "M", "W", "D", "H", "m", "S"].iter().enumerate() {
+            if let Some(count) = captures.get(group + 1) {
+                let count = count
+                    .as_str()
+                    .parse()
+                    .map_err(|_| format!("{} is too large", count.as_str()))?;
+                parsed.add(count, unit)?;
+            }
+        }
+        Ok(parsed)
+    }
+
+    /// Parse a list of counts and units like "1 day, 12 hours" or "1h30m".
+    fn parse_units(interval: &str) -> std::result::Result<Interval, String> {
+        let pattern = Regex::new(r"^[\s,]*(?:and\s+)?([0-9]+)\W*([A-Za-z]+)").unwrap();
+        let mut parsed = Interval::default();
+        let mut rest = interval.trim();
+        if rest.is_empty() {
+            return Err("it is empty".to_string());
+        }
+        while !rest.is_empty() {
+            let captures = pattern
+                .captures(rest)
+                .ok_or_else(|| format!("expected a number and a unit at {:?}", rest))?;
+            let count = captures[1]
+                .parse()
+                .map_err(|_| format!("{} is too large", &captures[1]))?;
+            parsed.add(count, &captures[2])?;
+            rest = rest[captures[0].len()..].trim();
+        }
+        Ok(parsed)
+    }
+
+    /// The time this interval before `time`.
+    pub fn before<T: TimeZone>(&self, time: DateTime<T>) -> Option<DateTime<T>> {
+        time.checked_sub_months(Months::new(self.months))?
+            .checked_sub_days(Days::new(self.days))?
+            .checked_sub_signed(self.duration)
+    }
+
+    /// The time this interval after `time`.
+    pub fn after<T: TimeZone>(&self, time: DateTime<T>) -> Option<DateTime<T>> {
+        time.checked_add_months(Months::new(self.months))?
+            .checked_add_days(Days::new(self.days))?
+            .checked_add_signed(self.duration)
+    }
+}
+
+impl FromStr for Interval {
+    type Err = Error;
+
+    fn from_str(interval: &str) -> Result<Interval> {
+        let parsed = if interval.starts_with('P') {
+            Interval::parse_iso(interval)
+        } else {
+            Interval::parse_units(interval)
+        };
+        parsed.map_err(|reason| Error::Interval(interval.to_string(), reason))
+    }
+}
+
+/// Offset a DateTime<T> back by an interval string.
+///
+/// The interval might require use of Months or Days, so we cannot simply return a Duration.
+pub fn offset_by_interval<T: TimeZone>(now: DateTime<T>, interval: &str) -> Result<DateTime<T>> {
+    let offset = interval
+        .parse::<Interval>()?
+        .before(now.clone())
+        .ok_or_else(|| Error::Interval(interval.to_string(), "it is out of range".to_string()))?;
+    debug!(
+        "Offset {:?} by {} to get {:?}",
+        &now,
+        &(now.clone() - offset.clone()),
+        &offset
+    );
+    Ok(offset)
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::{TimeZone, Utc};
+
+    use super::*;
+
+    #[test]
+    fn test_offset_by_interval_month() {
+        let now = Utc.with_ymd_and_hms(2000, 7, 1, 0, 0, 0).unwrap();
+        assert_eq!(
+            offset_by_interval(now, "0 M").unwrap(),
+            Utc.with_ymd_and_hms(2000, 7, 1, 0, 0, 0).unwrap(),
+        );
+        assert_eq!(
+            offset_by_interval(now, "1M").unwrap(),
+            Utc.with_ymd_and_hms(2000, 6, 1, 0, 0, 0).unwrap(),
+        );
+        assert_eq!(
+            offset_by_interval(now, "6month").unwrap(),
+            Utc.with_ymd_and_hms(2000, 1, 1, 0, 0, 0).unwrap(),
+        );
+        assert_eq!(
+            offset_by_interval(now, "10 months").unwrap(),
+            Utc.with_ymd_and_hms(1999, 9, 1, 0, 0, 0).unwrap(),
+        );
+    }
+
+    #[test]
+    fn test_offset_by_interval_week() {
+        let now = Utc.with_ymd_and_hms(2000, 7, 28, 0, 0, 0).unwrap();
+        assert_eq!(
+            offset_by_interval(now, "0 w").unwrap(),
+            Utc.with_ymd_and_hms(2000, 7, 28, 0, 0, 0).unwrap(),
+        );
+        assert_eq!(
+            offset_by_interval(now, "1w").unwrap(),
+            Utc.with_ymd_and_hms(2000, 7, 21, 0, 0, 0).unwrap(),
+        );
+        assert_eq!(
+            offset_by_interval(now, "2week").unwrap(),
+            Utc.with_ymd_and_hms(2000, 7, 14, 0, 0, 0).unwrap(),
+        );
+        assert_eq!(
+            offset_by_interval(now, "4 weeks").unwrap(),
+            Utc.with_ymd_and_hms(2000, 6, 30, 0, 0, 0).unwrap(),
+        );
+    }
+
+    #[test]
+    fn test_offset_by_interval_day() {
+        let now = Utc.with_ymd_and_hms(2000, 7, 30, 0, 0, 0).unwrap();
+        assert_eq!(
+            offset_by_interval(now, "0 d").unwrap(),
+            Utc.with_ymd_and_hms(2000, 7, 30, 0, 0, 0).unwrap(),
+        );
+        assert_eq!(
+            offset_by_interval(now, "1d").unwrap(),
+            Utc.with_ymd_and_hms(2000, 7, 29, 0, 0, 0).unwrap(),
+        );
+        assert_eq!(
+            offset_by_interval(now, "2day").unwrap(),
+            Utc.with_ymd_and_hms(2000, 7, 28, 0, 0, 0).unwrap(),
+        );
+        assert_eq!(
+            offset_by_interval(now, "30 days").unwrap(),
+            Utc.with_ymd_and_hms(2000, 6, 30, 0, 0, 0).unwrap(),
+        );
+    }
+
+    #[test]
+    fn test_offset_by_interval_hour() {
+        let now = Utc.with_ymd_and_hms(2000, 7, 3, 0, 0, 0).unwrap();
+        assert_eq!(
+            offset_by_interval(now, "0 h").unwrap(),
+            Utc.with_ymd_and_hms(2000, 7, 3, 0, 0, 0).unwrap(),
+        );
+        assert_eq!(
+            offset_by_interval(now, "1h").unwrap(),
+            Utc.with_ymd_and_hms(2000, 7, 2, 23, 0, 0).unwrap(),
+        );
+        assert_eq!(
+            offset_by_interval(now, "24hour").unwrap(),
+            Utc.with_ymd_and_hms(2000, 7, 2, 0, 0, 0).unwrap(),
+        );
+        assert_eq!(
+            offset_by_interval(now, "30 hours").unwrap(),
+            Utc.with_ymd_and_hms(2000, 7, 1, 18, 0, 0).unwrap(),
+        );
+    }
+
+    #[test]
+    fn test_offset_by_interval_minute() {
+        let now = Utc.with_ymd_and_hms(2000, 7, 2, 0, 0, 0).unwrap();
+        assert_eq!(
+            offset_by_interval(now, "0 m").unwrap(),
+            Utc.with_ymd_and_hms(2000, 7, 2, 0, 0, 0).unwrap(),
+        );
+        assert_eq!(
+            offset_by_interval(now, "1m").unwrap(),
+            Utc.with_ymd_and_hms(2000, 7, 1, 23, 59, 0).unwrap(),
+        );
+        assert_eq!(
+            offset_by_interval(now, "30minute").unwrap(),
+            Utc.with_ymd_and_hms(2000, 7, 1, 23, 30, 0).unwrap(),
+        );
+        assert_eq!(
+            offset_by_interval(now, "90 minutes").unwrap(),
+            Utc.with_ymd_and_hms(2000, 7, 1, 22, 30, 0).unwrap(),
+        );
+    }
+
+    #[test]
+    fn test_offset_by_interval_second() {
+        let now = Utc.with_ymd_and_hms(2000, 7, 2, 0, 0, 0).unwrap();
+        assert_eq!(
+            offset_by_interval(now, "0 s").unwrap(),
+            Utc.with_ymd_and_hms(2000, 7, 2, 0, 0, 0).unwrap(),
+        );
+        assert_eq!(
+            offset_by_interval(now, "1s").unwrap(),
+            Utc.with_ymd_and_hms(2000, 7, 1, 23, 59, 59).unwrap(),
+        );
+        assert_eq!(
+            offset_by_interval(now, "30second").unwrap(),
+            Utc.with_ymd_and_hms(2000, 7, 1, 23, 59, 30).unwrap(),
+        );
+        assert_eq!(
+            offset_by_interval(now, "90 seconds").unwrap(),
+            Utc.with_ymd_and_hms(2000, 7, 1, 23, 58, 30).unwrap(),
+        );
+    }
+
+    #[test]
+    fn test_offset_by_interval_invalid() {
+        let now = Utc.with_ymd_and_hms(2000, 7, 2, 0, 0, 0).unwrap();
+        assert!(matches!(
+            offset_by_interval(now, "1 fortnight"),
+            Err(Error::Interval(..))
+        ));
+        assert!(matches!(
+            offset_by_interval(now, "99999999999 seconds"),
+            Err(Error::Interval(..))
+        ));
+    }
+
+    #[test]
+    fn test_offset_by_interval_year() {
+        let now = Utc.with_ymd_and_hms(2000, 2, 29, 0, 0, 0).unwrap();
+        assert_eq!(
+            offset_by_interval(now, "1 year").unwrap(),
+            Utc.with_ymd_and_hms(1999, 2, 28, 0, 0, 0).unwrap(),
+        );
+        assert_eq!(
+            offset_by_interval(now, "2y").unwrap(),
+            Utc.with_ymd_and_hms(1998, 2, 28, 0, 0, 0).unwrap(),
+        );
+    }
+
+    #[test]
+    fn test_offset_by_interval_compound() {
+        let now = Utc.with_ymd_and_hms(2000, 7, 2, 0, 0, 0).unwrap();
+        let expected = Utc.with_ymd_and_hms(2000, 6, 30, 12, 0, 0).unwrap();
+        assert_eq!(offset_by_interval(now, "1 day 12 hours").unwrap(), expected);
+        assert_eq!(
+            offset_by_interval(now, "1 day, 12 hours").unwrap(),
+            expected
+        );
+        assert_eq!(offset_by_interval(now, "1d and 12h").unwrap(), expected);
+        assert_eq!(offset_by_interval(now, "1d12h").unwrap(), expected);
+        assert_eq!(
+            offset_by_interval(now, "1h30m").unwrap(),
+            Utc.with_ymd_and_hms(2000, 7, 1, 22, 30, 0).unwrap(),
+        );
+        assert_eq!(
+            offset_by_interval(now, "1 year 1 month").unwrap(),
+            Utc.with_ymd_and_hms(1999, 6, 2, 0, 0, 0).unwrap(),
+        );
+    }
+
+    #[test]
+    fn test_offset_by_interval_iso() {
+        let now = Utc.with_ymd_and_hms(2000, 7, 2, 0, 0, 0).unwrap();
+        assert_eq!(
+            offset_by_interval(now, "P1DT12H").unwrap(),
+            Utc.with_ymd_and_hms(2000, 6, 30, 12, 0, 0).unwrap(),
+        );
+        assert_eq!(
+            offset_by_interval(now, "P1Y2M").unwrap(),
+            Utc.with_ymd_and_hms(1999, 5, 2, 0, 0, 0).unwrap(),
+        );
+        assert_eq!(
+            offset_by_interval(now, "PT1M30S").unwrap(),
+            Utc.with_ymd_and_hms(2000, 7, 1, 23, 58, 30).unwrap(),
+        );
+        assert_eq!(
+            offset_by_interval(now, "P2W").unwrap(),
+            Utc.with_ymd_and_hms(2000, 6, 18, 0, 0, 0).unwrap(),
+        );
+        for invalid in ["P", "PT", "P1H", "P1DT", "P1D2Y"] {
+            assert!(matches!(
+                offset_by_interval(now, invalid),
+                Err(Error::Interval(..))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_interval_errors() {
+        let reason = |interval: &str| match interval.parse::<Interval>() {
+            Err(Error::Interval(_, reason)) => reason,
+            parsed => panic!("{:?} parsed as {:?}", interval, parsed),
+        };
+        assert_eq!(reason(""), "it is empty");
+        assert_eq!(reason("1 fortnight"), "unknown unit \"fortnight\"");
+        assert_eq!(
+            reason("1 day and"),
+            "expected a number and a unit at \"and\""
+        );
+        assert_eq!(reason("day"), "expected a number and a unit at \"day\"");
+        assert_eq!(
+            reason("99999999999 seconds"),
+            "99999999999 seconds is too large"
+        );
+        // Each count fits, but their total doesn't
+        assert_eq!(
+            reason("4000000000w 4000000000w 4000000000w 4000000000w"),
+            "4000000000 w is too large"
+        );
+        let now = Utc.with_ymd_and_hms(2000, 7, 2, 0, 0, 0).unwrap();
+        assert!(matches!(
+            offset_by_interval(now, "4000000 years"),
+            Err(Error::Interval(..))
+        ));
+    }
+
+    #[test]
+    fn test_interval_after() {
+        let now = Utc.with_ymd_and_hms(2000, 1, 31, 0, 0, 0).unwrap();
+        let interval: Interval = "1 month 1 day".parse().unwrap();
+        assert_eq!(
+            interval.after(now),
+            Some(Utc.with_ymd_and_hms(2000, 3, 1, 0, 0, 0).unwrap())
+        );
+    }
+}
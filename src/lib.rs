@@ -1,3 +1,15 @@
+pub mod archive;
+pub mod catalog;
+pub mod checksum;
 pub mod commands;
 pub mod config;
+pub mod error;
+pub mod health;
+pub mod interval;
+pub mod lock;
+pub mod repository;
+pub mod retention;
+pub mod schedule;
+pub mod snapshot;
+pub mod stream;
 pub mod utils;
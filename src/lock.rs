@@ -0,0 +1,410 @@
+//! Lockfiles that stop two syncs from writing to, or pruning, the same backup dir at once.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use chrono::{DateTime, Utc};
+use log::{debug, warn};
+
+use crate::error::{Error, Result};
+use crate::stream::Destination;
+use crate::utils::{run, shell_quote};
+
+/// The name of the lockfile in a backup dir.
+pub const LOCK_FILE: &str = ".backer-upper.lock";
+
+/// The process holding a lock, as recorded in the lockfile.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Holder {
+    pub pid: u32,
+    pub hostname: String,
+    pub section: String,
+    pub since: DateTime<Utc>,
+}
+
+impl Holder {
+    /// This process, syncing `section`.
+    fn current(section: &str) -> Holder {
+        Holder {
+            pid: std::process::id(),
+            hostname: hostname(),
+            section: section.to_string(),
+            since: Utc::now(),
+        }
+    }
+
+    fn parse(path: &Path, contents: &str) -> Result<Holder> {
+        let corrupt = || {
+            Error::Corrupt(
+                path.to_path_buf(),
+                "the lock is unreadable, remove it if nothing is syncing".to_string(),
+            )
+        };
+        let mut lines = contents.lines();
+        let mut line = || lines.next().ok_or_else(corrupt);
+        Ok(Holder {
+            pid: line()?.parse().map_err(|_| corrupt())?,
+            hostname: line()?.to_string(),
+            section: line()?.to_string(),
+            since: line()?.parse().map_err(|_| corrupt())?,
+        })
+    }
+
+    fn contents(&self) -> String {
+        format!(
+            "{}\n{}\n{}\n{}\n",
+            self.pid,
+            self.hostname,
+            self.section,
+            self.since.to_rfc3339()
+        )
+    }
+
+    /// Test if the holder has gone away without removing its lock. Only processes on this host
+    /// can be checked, so locks held from other hosts are never stale.
+    fn is_stale(&self) -> bool {
+        self.hostname == hostname() && !is_running(self.pid)
+    }
+}
+
+impl fmt::Display for Holder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} on {} (pid {}) since {}",
+            self.section, self.hostname, self.pid, self.since
+        )
+    }
+}
+
+/// The name of this host.
+fn hostname() -> String {
+    let mut name = [0u8; 256];
+    // SAFETY: the buffer is as long as the length passed
+    if unsafe { libc::gethostname(name.as_mut_ptr().cast(), name.len()) } != 0 {
+        return "localhost".to_string();
+    }
+    let end = name
+        .iter()
+        .position(|byte| *byte == 0)
+        .unwrap_or(name.len());
+    String::from_utf8_lossy(&name[..end]).into_owned()
+}
+
+/// Test if a process on this host is running.
+fn is_running(pid: u32) -> bool {
+    // 0 and negative pids signal groups of processes rather than a single one
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    if pid <= 0 {
+        return false;
+    }
+    // SAFETY: signal 0 only checks whether the process exists
+    let signalled = unsafe { libc::kill(pid, 0) } == 0;
+    signalled || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// The directory that section locks are kept in on this host: `backer-upper-locks` in
+/// `$XDG_RUNTIME_DIR`, or in the temporary directory if that isn't set. The name is distinct
+/// enough that globs for other files in the temporary directory don't pick the locks up.
+pub fn section_lock_dir() -> PathBuf {
+    std::env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(std::env::temp_dir)
+        .join("backer-upper-locks")
+}
+
+/// Turn a section name into a file name, escaping anything but letters, digits, `-` and `_`.
+fn escape(section: &str) -> String {
+    section
+        .bytes()
+        .map(|byte| match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' => (byte as char).to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+/// A lock on a backup dir or a section, which is released when dropped.
+#[derive(Debug)]
+pub struct Lock {
+    file: Destination,
+    holder: Holder,
+}
+
+impl Lock {
+    /// Lock a backup dir, on `host` if there is one, for syncing `section`. Fails with
+    /// `Error::Locked` if another process holds the lock, and takes over the lock of a process on
+    /// this host that has exited without removing it.
+    ///
+    /// With a `host`, the lock only exists on that host. The holder it records is still the
+    /// process on this host that took it, so stale locks are only recognised by syncs from the
+    /// same host. If the host can't be reached, the lock can't be checked either, and this fails
+    /// with the error from `ssh` rather than `Error::Locked`.
+    pub fn acquire(host: &Option<String>, dir: &Path, section: &str) -> Result<Lock> {
+        Lock::acquire_file(Destination::new(host, dir.join(LOCK_FILE)), section)
+    }
+
+    /// Lock a section by its name, so that it is never synced twice at once from this host, even
+    /// by sync files that give it different dirs. Section locks are kept in `section_lock_dir`.
+    pub fn acquire_section(section: &str) -> Result<Lock> {
+        let dir = section_lock_dir();
+        std::fs::create_dir_all(&dir)?;
+        let file = dir.join(format!("{}.lock", escape(section)));
+        Lock::acquire_file(Destination::Local(file), section)
+    }
+
+    /// Lock both a section and its backup dir, as every sync and prune does.
+    pub fn acquire_both(host: &Option<String>, dir: &Path, section: &str) -> Result<(Lock, Lock)> {
+        let section_lock = Lock::acquire_section(section)?;
+        Ok((section_lock, Lock::acquire(host, dir, section)?))
+    }
+
+    fn acquire_file(file: Destination, section: &str) -> Result<Lock> {
+        let holder = Holder::current(section);
+        // Once to find a stale lock, once more to take its place, and again if the lock was
+        // released between creating ours and reading it
+        let mut existing = None;
+        for _ in 0..3 {
+            let contents = match create(&file, &holder)? {
+                None => {
+                    debug!("Locked {:?}", file);
+                    return Ok(Lock { file, holder });
+                }
+                Some(contents) if contents.is_empty() => continue,
+                Some(contents) => contents,
+            };
+            let held = Holder::parse(file.path(), &contents)?;
+            if !held.is_stale() {
+                return Err(Error::Locked(file.path().to_path_buf(), held));
+            }
+            // Another process might be taking over the same stale lock, in which case only one
+            // of them removes it and the other finds the lock of the first
+            if remove_if_holds(&file, &contents, holder.pid)? {
+                warn!("Removed the stale lock {:?} of {}", file, held);
+            }
+            existing = Some(held);
+        }
+        match existing {
+            Some(held) => Err(Error::Locked(file.path().to_path_buf(), held)),
+            None => Err(std::io::Error::other(format!(
+                "{} kept changing hands",
+                file.path().display()
+            ))
+            .into()),
+        }
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        match remove_if_holds(&self.file, &self.holder.contents(), self.holder.pid) {
+            Ok(true) => debug!("Unlocked {:?}", self.file),
+            Ok(false) => warn!(
+                "The lock {:?} was taken over before it was released",
+                self.file
+            ),
+            Err(err) => warn!("Failed to remove the lock {:?}: {}", self.file, err),
+        }
+    }
+}
+
+/// Remove a lockfile only if it still holds `contents`, returning whether it did. The lockfile is
+/// first moved to a name that only the process `pid` uses, so that no other process can take the
+/// lock between checking it and removing it. If it turns out to hold someone else's lock, it is
+/// put back.
+fn remove_if_holds(file: &Destination, contents: &str, pid: u32) -> Result<bool> {
+    let moved = file.sidecar(&format!(".{}.removing", pid));
+    match file {
+        Destination::Local(path) => {
+            match std::fs::rename(path, moved.path()) {
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+                moved => moved?,
+            }
+            let holds = std::fs::read_to_string(moved.path())? == contents;
+            if !holds {
+                // Unless yet another lock has been taken since
+                match std::fs::hard_link(moved.path(), path) {
+                    Err(err) if err.kind() != std::io::ErrorKind::AlreadyExists => {
+                        warn!("Failed to put back the lock {:?}: {}", path, err)
+                    }
+                    _ => (),
+                }
+            }
+            std::fs::remove_file(moved.path())?;
+            Ok(holds)
+        }
+        Destination::Remote { host, path } => {
+            let (moved, path) = (shell_quote(moved.path()), shell_quote(path));
+            let output = run(Command::new("ssh").arg(host).arg(format!(
+                "mv -f {1} {2} 2>/dev/null || exit 0; \
+                 if printf %s {0} | cmp -s - {2}; then rm -f {2} && echo removed; \
+                 else ln {2} {1} 2>/dev/null; rm -f {2}; fi",
+                shell_quote(Path::new(contents)),
+                path,
+                moved
+            )))
+            .map_err(Error::remote(host))?;
+            Ok(output == "removed\n")
+        }
+    }
+}
+
+/// Create a lockfile holding `holder` if there isn't one already, or return what the existing
+/// one holds. The lock is written next to the lockfile and then linked into place, so that other
+/// processes never see it half-written.
+fn create(file: &Destination, holder: &Holder) -> Result<Option<String>> {
+    let staged = file.sidecar(&format!(".{}", holder.pid));
+    match file {
+        Destination::Local(path) => {
+            std::fs::write(staged.path(), holder.contents())?;
+            let linked = std::fs::hard_link(staged.path(), path);
+            std::fs::remove_file(staged.path())?;
+            match linked {
+                Ok(()) => Ok(None),
+                Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {
+                    match std::fs::read_to_string(path) {
+                        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                            Ok(Some(String::new()))
+                        }
+                        contents => Ok(Some(contents?)),
+                    }
+                }
+                Err(err) => Err(err.into()),
+            }
+        }
+        Destination::Remote { host, path } => {
+            let (staged, path) = (shell_quote(staged.path()), shell_quote(path));
+            let output = run(Command::new("ssh").arg(host).arg(format!(
+                "printf %s {} > {1} && if ln {1} {2} 2>/dev/null; \
+                 then rm -f {1} && echo locked; else rm -f {1} && cat {2} 2>/dev/null; true; fi",
+                shell_quote(Path::new(&holder.contents())),
+                staged,
+                path
+            )))
+            .map_err(Error::remote(host))?;
+            if output == "locked\n" {
+                Ok(None)
+            } else {
+                Ok(Some(output))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn lock_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("backer-upper-lock-{}", name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_lock() {
+        let dir = lock_dir("held");
+        let lock = Lock::acquire(&None, &dir, "first").unwrap();
+        match Lock::acquire(&None, &dir, "second") {
+            Err(Error::Locked(path, holder)) => {
+                assert_eq!(path, dir.join(LOCK_FILE));
+                assert_eq!(holder.pid, std::process::id());
+                assert_eq!(holder.section, "first");
+            }
+            locked => panic!("locked twice: {:?}", locked),
+        }
+        drop(lock);
+        assert!(!dir.join(LOCK_FILE).exists());
+        Lock::acquire(&None, &dir, "second").unwrap();
+    }
+
+    #[test]
+    fn test_lock_section() {
+        let lock = Lock::acquire_section("test/lock section").unwrap();
+        let file = section_lock_dir().join("test%2Flock%20section.lock");
+        assert!(file.exists());
+        assert!(matches!(
+            Lock::acquire_section("test/lock section"),
+            Err(Error::Locked(path, _)) if path == file
+        ));
+        // The same section in another dir is still locked
+        let dir = lock_dir("section");
+        assert!(matches!(
+            Lock::acquire_both(&None, &dir, "test/lock section"),
+            Err(Error::Locked(..))
+        ));
+        assert!(!dir.join(LOCK_FILE).exists());
+        drop(lock);
+        assert!(!file.exists());
+        Lock::acquire_both(&None, &dir, "test/lock section").unwrap();
+    }
+
+    #[test]
+    fn test_lock_stale() {
+        let dir = lock_dir("stale");
+        let mut exited = Command::new("true").spawn().unwrap();
+        exited.wait().unwrap();
+        let stale = Holder {
+            pid: exited.id(),
+            ..Holder::current("stale")
+        };
+        std::fs::write(dir.join(LOCK_FILE), stale.contents()).unwrap();
+        let lock = Lock::acquire(&None, &dir, "section").unwrap();
+        drop(lock);
+        // A lock held from another host can't be checked, so it is kept
+        let elsewhere = Holder {
+            pid: exited.id(),
+            hostname: format!("not-{}", hostname()),
+            ..Holder::current("elsewhere")
+        };
+        std::fs::write(dir.join(LOCK_FILE), elsewhere.contents()).unwrap();
+        assert!(matches!(
+            Lock::acquire(&None, &dir, "section"),
+            Err(Error::Locked(_, holder)) if holder == elsewhere
+        ));
+    }
+
+    #[test]
+    fn test_remove_if_holds() {
+        let dir = lock_dir("remove");
+        let file = Destination::Local(dir.join(LOCK_FILE));
+        let ours = Holder::current("ours");
+        let theirs = Holder {
+            pid: ours.pid + 1,
+            ..Holder::current("theirs")
+        };
+        std::fs::write(file.path(), theirs.contents()).unwrap();
+        assert!(!remove_if_holds(&file, &ours.contents(), ours.pid).unwrap());
+        assert_eq!(
+            std::fs::read_to_string(file.path()).unwrap(),
+            theirs.contents()
+        );
+        assert!(remove_if_holds(&file, &theirs.contents(), ours.pid).unwrap());
+        assert!(!file.exists().unwrap());
+        assert!(!remove_if_holds(&file, &theirs.contents(), ours.pid).unwrap());
+        // A lock taken over from this process is left alone when it is dropped
+        let lock = Lock::acquire(&None, &dir, "ours").unwrap();
+        std::fs::write(file.path(), theirs.contents()).unwrap();
+        drop(lock);
+        assert_eq!(
+            std::fs::read_to_string(file.path()).unwrap(),
+            theirs.contents()
+        );
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_holder_parse() {
+        let holder = Holder::current("section");
+        let path = Path::new(LOCK_FILE);
+        assert_eq!(Holder::parse(path, &holder.contents()).unwrap(), holder);
+        assert!(matches!(
+            Holder::parse(path, "123\n"),
+            Err(Error::Corrupt(..))
+        ));
+    }
+}
@@ -1,10 +1,22 @@
 use clap::Parser;
 use log::error;
 
+use backer_upper::commands::Cli;
+use backer_upper::health::State;
+
 fn main() {
     env_logger::init();
-    let cli = backer_upper::commands::Cli::parse();
-    cli.run_command().unwrap_or_else(|x| {
-        error!("{}", x);
-    });
+    let cli = match Cli::try_parse() {
+        Ok(cli) => cli,
+        // Usage errors from `status` are UNKNOWN, rather than clap's 2 which is CRITICAL
+        Err(err) if err.use_stderr() && Cli::is_status(std::env::args()) => {
+            let _ = err.print();
+            std::process::exit(State::Unknown.exit_code());
+        }
+        Err(err) => err.exit(),
+    };
+    if let Err(err) = cli.run_command() {
+        error!("{}", err);
+        std::process::exit(cli.exit_code(&err));
+    }
 }
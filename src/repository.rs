@@ -0,0 +1,618 @@
+//! A deduplicating alternative to storing a whole archive for every backup.
+//!
+//! Files are split into content-defined chunks which are stored once, named by their hash, under
+//! `chunks/` in the backup directory. Each backup is a small manifest listing the chunks of every
+//! file. Pruning a backup only deletes its manifest, and collecting garbage afterwards frees any
+//! chunks that no remaining manifest refers to.
+
+use std::collections::HashSet;
+use std::fs::{File, FileTimes};
+use std::io::{Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use fastcdc::v2020::StreamCDC;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::archive::{
+    archive_name, compile_globs, is_requested, resolve_globs, walk, EntryInfo, Progress, Report,
+    Target,
+};
+use crate::error::{Error, Result};
+use crate::snapshot::Kind;
+use crate::stream::{store, write_to, Destination, Written};
+
+/// The directory chunks are stored in, relative to the backup directory.
+pub const CHUNKS_DIR: &str = "chunks";
+/// The directory listing which chunks each manifest uses, relative to `CHUNKS_DIR`.
+const REFS_DIR: &str = "refs";
+
+const MIN_CHUNK_SIZE: u32 = 256 * 1024;
+const AVG_CHUNK_SIZE: u32 = 1024 * 1024;
+const MAX_CHUNK_SIZE: u32 = 4 * 1024 * 1024;
+
+/// A single file, directory or symlink in a manifest.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub path: PathBuf,
+    pub kind: Kind,
+    pub mode: u32,
+    pub mtime: SystemTime,
+    pub size: u64,
+    /// The hashes of the chunks making up a file, in order.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub chunks: Vec<String>,
+    /// The target of a symlink.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<PathBuf>,
+}
+
+/// Everything needed to restore a backup from the chunks in a repository.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Manifest {
+    pub entries: Vec<Entry>,
+}
+
+impl Manifest {
+    /// Test if the start of a backup looks like a manifest rather than a gzipped archive.
+    pub fn is_manifest(start: &[u8]) -> bool {
+        start.first() == Some(&b'{')
+    }
+
+    pub fn read(reader: impl Read) -> Result<Manifest> {
+        Ok(serde_json::from_reader(reader)?)
+    }
+
+    pub fn write(&self, writer: impl Write) -> Result<()> {
+        Ok(serde_json::to_writer(writer, self)?)
+    }
+
+    /// List the entries of a manifest, optionally only those matching `globs`.
+    pub fn list(&self, globs: &[String]) -> Result<Vec<EntryInfo>> {
+        let patterns = compile_globs(globs)?;
+        Ok(self
+            .entries
+            .iter()
+            .map(|entry| EntryInfo {
+                path: archive_name(&entry.path),
+                kind: entry.kind,
+                size: entry.size,
+                mode: entry.mode,
+                mtime: entry.mtime,
+                target: entry.target.clone(),
+            })
+            .filter(|entry| is_requested(&entry.path, &patterns))
+            .collect())
+    }
+
+    pub(crate) fn chunks(&self) -> HashSet<&String> {
+        self.entries
+            .iter()
+            .flat_map(|entry| entry.chunks.iter())
+            .collect()
+    }
+}
+
+fn hash(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+/// Test if a string is a SHA-256 as `hash` formats it.
+fn is_hash(hash: &str) -> bool {
+    hash.len() == 64 && hash.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// A directory of manifests sharing a store of chunks.
+pub struct Repository {
+    dir: PathBuf,
+    gpg_id: Option<String>,
+}
+
+impl Repository {
+    pub fn new(dir: &Path, gpg_id: &Option<String>) -> Repository {
+        Repository {
+            dir: dir.to_path_buf(),
+            gpg_id: gpg_id.clone(),
+        }
+    }
+
+    /// Find the repository that a manifest belongs to.
+    pub fn of_manifest(manifest: &Path, gpg_id: &Option<String>) -> Repository {
+        Repository::new(manifest.parent().unwrap_or(Path::new(".")), gpg_id)
+    }
+
+    /// The path a chunk is stored at. Hashes are read from manifests and refs, which could have
+    /// been tampered with, so anything but a SHA-256 in lowercase hex is refused.
+    fn chunk_path(&self, hash: &str) -> Result<PathBuf> {
+        if !is_hash(hash) {
+            return Err(Error::Corrupt(
+                self.dir.join(CHUNKS_DIR),
+                format!("{:?} is not the hash of a chunk", hash),
+            ));
+        }
+        Ok(self.dir.join(CHUNKS_DIR).join(&hash[..2]).join(hash))
+    }
+
+    fn refs_path(&self, name: &str) -> PathBuf {
+        self.dir.join(CHUNKS_DIR).join(REFS_DIR).join(name)
+    }
+
+    /// Store a chunk unless it is already stored, returning whether it was new.
+    fn store_chunk(&self, hash: &str, data: &[u8]) -> Result<bool> {
+        let path = self.chunk_path(hash)?;
+        if path.exists() {
+            return Ok(false);
+        }
+        std::fs::create_dir_all(path.parent().expect("chunks are in a directory"))?;
+        // Chunks are assumed to be intact if they exist, so never leave half of one behind
+        let partial = path.with_extension("partial");
+        write_to(&Destination::Local(partial.clone()), &self.gpg_id, |sink| {
+            let mut encoder = GzEncoder::new(sink, Compression::default());
+            encoder.write_all(data)?;
+            encoder.finish()?;
+            Ok(())
+        })?;
+        std::fs::rename(partial, path)?;
+        Ok(true)
+    }
+
+    /// Load a chunk, checking that it has not been corrupted.
+    pub fn load_chunk(&self, hash: &str) -> Result<Vec<u8>> {
+        let path = self.chunk_path(hash)?;
+        let mut source = Destination::Local(path.clone()).open(&self.gpg_id)?;
+        let mut data = vec![];
+        let read = GzDecoder::new(&mut source).read_to_end(&mut data);
+        source.finish()?;
+        read?;
+        if self::hash(&data) != hash {
+            return Err(Error::Corrupt(
+                path,
+                "chunk does not match its hash".to_string(),
+            ));
+        }
+        Ok(data)
+    }
+
+    /// Split a file into chunks, storing any that are new.
+    fn store_file(&self, path: &Path, progress: &mut Progress) -> Result<Vec<String>> {
+        let mut hashes = vec![];
+        let chunker = StreamCDC::new(
+            File::open(path)?,
+            MIN_CHUNK_SIZE,
+            AVG_CHUNK_SIZE,
+            MAX_CHUNK_SIZE,
+        );
+        for chunk in chunker {
+            let chunk = chunk.map_err(std::io::Error::from)?;
+            let hash = hash(&chunk.data);
+            if self.store_chunk(&hash, &chunk.data)? {
+                progress.entries += 1;
+                progress.bytes += chunk.length as u64;
+            }
+            hashes.push(hash);
+        }
+        Ok(hashes)
+    }
+
+    /// Back up files as a new manifest named `name`.
+    pub fn backup(&self, globs: &[String], name: &str) -> Result<(Manifest, Written)> {
+        let mut manifest = Manifest::default();
+        // Counts the new chunks rather than the files
+        let mut progress = Progress::default();
+        for path in walk(&resolve_globs(globs)?)?.into_iter() {
+            debug!("Storing {:?}", path);
+            let metadata = std::fs::symlink_metadata(&path)?;
+            let mut entry = Entry {
+                path: path.clone(),
+                kind: Kind::Other,
+                mode: metadata.permissions().mode(),
+                mtime: metadata.modified()?,
+                size: metadata.len(),
+                chunks: vec![],
+                target: None,
+            };
+            if metadata.is_file() {
+                entry.kind = Kind::File;
+                entry.chunks = self.store_file(&path, &mut progress)?;
+            } else if metadata.is_dir() {
+                entry.kind = Kind::Dir;
+            } else if metadata.is_symlink() {
+                entry.kind = Kind::Symlink;
+                entry.target = Some(std::fs::read_link(&path)?);
+            }
+            manifest.entries.push(entry);
+        }
+        info!(
+            "Stored {} new chunks ({} bytes) for {} entries",
+            progress.entries,
+            progress.bytes,
+            manifest.entries.len()
+        );
+        // The refs go first, so that garbage collection never sees a manifest without them
+        let refs: Vec<&String> = manifest.chunks().into_iter().collect();
+        std::fs::create_dir_all(
+            self.refs_path(name)
+                .parent()
+                .expect("refs are in a directory"),
+        )?;
+        store(&Destination::Local(self.refs_path(name)), &None, |sink| {
+            for hash in refs.iter() {
+                writeln!(sink, "{}", hash)?;
+            }
+            Ok(())
+        })?;
+        let written = store(
+            &Destination::Local(self.dir.join(name)),
+            &self.gpg_id,
+            |sink| manifest.write(sink),
+        )?;
+        Ok((manifest, written))
+    }
+
+    /// Restore the entries of a manifest to `target`, optionally only those matching `globs`.
+    pub fn restore(
+        &self,
+        manifest: &Manifest,
+        globs: &[String],
+        target: &Target,
+        report: &mut Report,
+    ) -> Result<Progress> {
+        let patterns = compile_globs(globs)?;
+        std::fs::create_dir_all(&target.root)?;
+        let root = std::fs::canonicalize(&target.root)?;
+        // Check the whole manifest first, so that a corrupt one restores nothing
+        for hash in manifest.chunks() {
+            self.chunk_path(hash)?;
+        }
+        let mut progress = Progress::default();
+        let mut dirs = vec![];
+        for entry in manifest.entries.iter() {
+            let name = archive_name(&entry.path);
+            if !is_requested(&name, &patterns) {
+                continue;
+            }
+            let Some(path) = target.path_of(&name) else {
+                continue;
+            };
+            if !target.contains(&path, &root)? {
+                warn!("Skipped suspicious entry {:?}", name);
+                continue;
+            }
+            if !target.prepare(&path, entry.kind == Kind::Dir, entry.mtime, report)? {
+                continue;
+            }
+            debug!("Restoring {:?} to {:?}", name, path);
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            // Replace a symlink or file rather than writing through it, since it could be linked
+            // to something outside of the target
+            if std::fs::symlink_metadata(&path).is_ok_and(|existing| !existing.is_dir()) {
+                std::fs::remove_file(&path)?;
+            }
+            match entry.kind {
+                Kind::Dir => {
+                    std::fs::create_dir_all(&path)?;
+                    // Restoring the contents would change the mtime, so set it afterwards
+                    dirs.push((path, entry));
+                    progress.entries += 1;
+                    continue;
+                }
+                Kind::File => {
+                    let mut file = File::create(&path)?;
+                    for hash in entry.chunks.iter() {
+                        file.write_all(&self.load_chunk(hash)?)?;
+                    }
+                    file.set_times(FileTimes::new().set_modified(entry.mtime))?;
+                    progress.bytes += entry.size;
+                }
+                Kind::Symlink => {
+                    let Some(target) = entry.target.as_ref() else {
+                        return Err(Error::Corrupt(
+                            self.dir.clone(),
+                            format!("the symlink {:?} in a manifest has no target", name),
+                        ));
+                    };
+                    std::os::unix::fs::symlink(target, &path)?;
+                    progress.entries += 1;
+                    continue;
+                }
+                Kind::Other => {
+                    debug!("Skipping special file {:?}", name);
+                    continue;
+                }
+            }
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(entry.mode))?;
+            progress.entries += 1;
+        }
+        for (path, entry) in dirs.iter().rev() {
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(entry.mode))?;
+            File::open(path)?.set_times(FileTimes::new().set_modified(entry.mtime))?;
+        }
+        info!(
+            "Restored {} entries ({} bytes)",
+            progress.entries, progress.bytes
+        );
+        Ok(progress)
+    }
+
+    /// The size of the chunks used by the given manifests, counting chunks they share once.
+    /// Manifests without refs, like those of a backup that was interrupted, use no chunks.
+    pub fn chunks_size(&self, names: &[&str]) -> Result<u64> {
+        let mut used = HashSet::new();
+        for name in names.iter() {
+            match std::fs::read_to_string(self.refs_path(name)) {
+                Ok(contents) => used.extend(contents.lines().map(str::to_string)),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        let mut size = 0;
+        for hash in used.iter() {
+            size += std::fs::metadata(self.chunk_path(hash)?)?.len();
+        }
+        Ok(size)
+    }
+
+    /// Delete every chunk that is not used by a remaining manifest, returning how many were
+    /// deleted.
+    pub fn collect_garbage(&self) -> Result<usize> {
+        let refs_dir = self.dir.join(CHUNKS_DIR).join(REFS_DIR);
+        if !refs_dir.exists() {
+            return Ok(0);
+        }
+        let mut live = HashSet::new();
+        for refs in std::fs::read_dir(&refs_dir)? {
+            let refs = refs?;
+            if !self.dir.join(refs.file_name()).exists() {
+                debug!("Manifest {:?} is gone", refs.file_name());
+                std::fs::remove_file(refs.path())?;
+                continue;
+            }
+            let contents = std::fs::read_to_string(refs.path())?;
+            live.extend(contents.lines().map(str::to_string));
+        }
+        let mut removed = 0;
+        for prefix in std::fs::read_dir(self.dir.join(CHUNKS_DIR))? {
+            let prefix = prefix?;
+            if prefix.file_name() == REFS_DIR || !prefix.file_type()?.is_dir() {
+                continue;
+            }
+            for chunk in std::fs::read_dir(prefix.path())? {
+                let chunk = chunk?;
+                if !live.contains(chunk.file_name().to_string_lossy().as_ref()) {
+                    debug!("Removing unused chunk {:?}", chunk.path());
+                    std::fs::remove_file(chunk.path())?;
+                    removed += 1;
+                }
+            }
+        }
+        info!("Removed {} unused chunks", removed);
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("backer-upper-repository-{}", name));
+        if dir.exists() {
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+        std::fs::create_dir_all(dir.join("src/dir")).unwrap();
+        std::fs::create_dir_all(dir.join("repo")).unwrap();
+        std::fs::write(dir.join("src/a.txt"), "same").unwrap();
+        std::fs::write(dir.join("src/dir/b.txt"), "same").unwrap();
+        dir
+    }
+
+    fn chunk_count(repository: &Repository) -> usize {
+        std::fs::read_dir(repository.dir.join(CHUNKS_DIR))
+            .unwrap()
+            .map(|prefix| prefix.unwrap())
+            .filter(|prefix| prefix.file_name() != REFS_DIR)
+            .map(|prefix| std::fs::read_dir(prefix.path()).unwrap().count())
+            .sum()
+    }
+
+    #[test]
+    fn test_is_manifest() {
+        assert!(Manifest::is_manifest(b"{\"entries\":[]}"));
+        assert!(!Manifest::is_manifest(&[0x1f, 0x8b]));
+        assert!(!Manifest::is_manifest(&[]));
+    }
+
+    #[test]
+    fn test_backup_deduplicates() {
+        let dir = test_dir("dedup");
+        let repository = Repository::new(&dir.join("repo"), &None);
+        let globs = vec![format!("{}/src", dir.display())];
+        let (manifest, _) = repository.backup(&globs, "one").unwrap();
+        assert_eq!(manifest.entries.len(), 4);
+        // Both files have the same contents
+        assert_eq!(chunk_count(&repository), 1);
+        repository.backup(&globs, "two").unwrap();
+        assert_eq!(chunk_count(&repository), 1);
+        assert_eq!(
+            std::fs::read_to_string(repository.refs_path("two")).unwrap(),
+            format!("{}\n", hash(b"same"))
+        );
+    }
+
+    #[test]
+    fn test_restore() {
+        let dir = test_dir("restore");
+        let repository = Repository::new(&dir.join("repo"), &None);
+        let (manifest, _) = repository
+            .backup(&[format!("{}/src", dir.display())], "one")
+            .unwrap();
+        let root = dir.join("root");
+        repository
+            .restore(&manifest, &[], &Target::new(&root), &mut Report::default())
+            .unwrap();
+        let restored = root.join(archive_name(&dir));
+        assert_eq!(
+            std::fs::read_to_string(restored.join("src/dir/b.txt")).unwrap(),
+            "same"
+        );
+    }
+
+    #[test]
+    fn test_restore_stays_in_root() {
+        let dir = test_dir("escape");
+        let repository = Repository::new(&dir.join("repo"), &None);
+        let outside = dir.join("outside");
+        std::fs::create_dir_all(&outside).unwrap();
+        std::fs::write(outside.join("victim"), "original").unwrap();
+        let hash = hash(b"evil");
+        repository.store_chunk(&hash, b"evil").unwrap();
+        let entry = |path: &str, kind: Kind| Entry {
+            path: path.into(),
+            kind,
+            mode: 0o644,
+            mtime: SystemTime::now(),
+            size: 4,
+            chunks: if kind == Kind::File {
+                vec![hash.clone()]
+            } else {
+                vec![]
+            },
+            target: (kind == Kind::Symlink).then(|| outside.clone()),
+        };
+        let manifest = Manifest {
+            entries: vec![
+                entry("/link", Kind::Symlink),
+                entry("/link/newdir/deeper/passwd", Kind::File),
+                entry("/victim", Kind::File),
+            ],
+        };
+        let root = dir.join("root");
+        std::fs::create_dir_all(&root).unwrap();
+        // An existing symlink is replaced rather than written through
+        std::os::unix::fs::symlink(outside.join("victim"), root.join("victim")).unwrap();
+        repository
+            .restore(&manifest, &[], &Target::new(&root), &mut Report::default())
+            .unwrap();
+        assert!(!outside.join("newdir").exists());
+        assert_eq!(
+            std::fs::read_to_string(outside.join("victim")).unwrap(),
+            "original"
+        );
+        assert_eq!(
+            std::fs::read_to_string(root.join("victim")).unwrap(),
+            "evil"
+        );
+    }
+
+    #[test]
+    fn test_restore_rejects_corrupt_manifests() {
+        let dir = test_dir("corrupt-manifest");
+        let repository = Repository::new(&dir.join("repo"), &None);
+        let root = dir.join("root");
+        let entry = Entry {
+            path: "/file".into(),
+            kind: Kind::File,
+            mode: 0o644,
+            mtime: SystemTime::now(),
+            size: 4,
+            chunks: vec![],
+            target: None,
+        };
+        let restore = |entry: &Entry| {
+            let manifest = Manifest {
+                entries: vec![entry.clone()],
+            };
+            repository.restore(&manifest, &[], &Target::new(&root), &mut Report::default())
+        };
+        for bad in ["a", "é", "../../../../x", &hash(b"x").to_uppercase()] {
+            let entry = Entry {
+                chunks: vec![bad.to_string()],
+                ..entry.clone()
+            };
+            assert!(
+                matches!(restore(&entry), Err(Error::Corrupt(..))),
+                "{}",
+                bad
+            );
+        }
+        assert!(!root.join("file").exists());
+        let symlink = Entry {
+            kind: Kind::Symlink,
+            ..entry
+        };
+        assert!(matches!(restore(&symlink), Err(Error::Corrupt(..))));
+    }
+
+    #[test]
+    fn test_restore_replaces_hard_links() {
+        let dir = test_dir("hard-link");
+        let repository = Repository::new(&dir.join("repo"), &None);
+        let (manifest, _) = repository
+            .backup(&[format!("{}/src/a.txt", dir.display())], "one")
+            .unwrap();
+        let root = dir.join("root");
+        let restored = root.join(archive_name(&dir)).join("src/a.txt");
+        std::fs::create_dir_all(restored.parent().unwrap()).unwrap();
+        let outside = dir.join("outside");
+        std::fs::write(&outside, "original").unwrap();
+        std::fs::hard_link(&outside, &restored).unwrap();
+        repository
+            .restore(&manifest, &[], &Target::new(&root), &mut Report::default())
+            .unwrap();
+        assert_eq!(std::fs::read_to_string(&restored).unwrap(), "same");
+        assert_eq!(std::fs::read_to_string(&outside).unwrap(), "original");
+    }
+
+    #[test]
+    fn test_collect_garbage() {
+        let dir = test_dir("garbage");
+        let repository = Repository::new(&dir.join("repo"), &None);
+        let globs = vec![format!("{}/src", dir.display())];
+        repository.backup(&globs, "one").unwrap();
+        std::fs::write(dir.join("src/a.txt"), "different").unwrap();
+        repository.backup(&globs, "two").unwrap();
+        assert_eq!(chunk_count(&repository), 2);
+        let same = std::fs::metadata(repository.chunk_path(&hash(b"same")).unwrap())
+            .unwrap()
+            .len();
+        assert_eq!(repository.chunks_size(&["one"]).unwrap(), same);
+        assert!(repository.chunks_size(&["one", "two"]).unwrap() > same);
+        assert_eq!(repository.collect_garbage().unwrap(), 0);
+        // Like a backup that was interrupted after its refs were written
+        std::fs::write(repository.refs_path("three"), "").unwrap();
+        assert_eq!(repository.collect_garbage().unwrap(), 0);
+        assert!(!repository.refs_path("three").exists());
+        std::fs::remove_file(dir.join("repo/two")).unwrap();
+        assert_eq!(repository.collect_garbage().unwrap(), 1);
+        assert_eq!(chunk_count(&repository), 1);
+        assert!(!repository.refs_path("two").exists());
+    }
+
+    #[test]
+    fn test_load_chunk_detects_corruption() {
+        let dir = test_dir("corrupt");
+        let repository = Repository::new(&dir.join("repo"), &None);
+        let hash = hash(b"data");
+        repository.store_chunk(&hash, b"data").unwrap();
+        assert_eq!(repository.load_chunk(&hash).unwrap(), b"data");
+        let mut encoder = GzEncoder::new(vec![], Compression::default());
+        encoder.write_all(b"tampered").unwrap();
+        std::fs::write(
+            repository.chunk_path(&hash).unwrap(),
+            encoder.finish().unwrap(),
+        )
+        .unwrap();
+        assert!(matches!(
+            repository.load_chunk(&hash),
+            Err(Error::Corrupt(_, _))
+        ));
+    }
+}
@@ -0,0 +1,577 @@
+//! Rules for thinning a config's backups into a history on several timescales, keeping the
+//! newest backup of each of the last few hours, days, weeks, months and years, and limits on how
+//! old and how large that history can get.
+
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+use chrono::{DateTime, Local, Utc};
+use log::warn;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::catalog::{Catalog, CatalogEntry};
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::interval::offset_by_interval;
+use crate::repository::Repository;
+
+/// Which backups to keep beyond the newest `copies`. A backup is kept if any rule keeps it, unless
+/// it is beyond one of the limits.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Retention {
+    /// Keep every backup taken within this interval, like "2 days".
+    pub keep_within: Option<String>,
+    pub keep_hourly: Option<usize>,
+    pub keep_daily: Option<usize>,
+    pub keep_weekly: Option<usize>,
+    pub keep_monthly: Option<usize>,
+    pub keep_yearly: Option<usize>,
+    /// Remove backups older than this interval, like "90 days", even if a rule keeps them.
+    pub max_age: Option<String>,
+    /// Remove the oldest backups until the rest take up no more than this, like "500 GiB".
+    pub max_total_size: Option<String>,
+    /// Never remove the newest this many backups, whatever the limits say. Defaults to 1.
+    pub min_copies: Option<usize>,
+}
+
+impl Retention {
+    /// Test if no rules or limits are set.
+    pub fn is_empty(&self) -> bool {
+        *self == Retention::default()
+    }
+
+    /// Test if any rule is set that picks backups to keep, as opposed to limits on what is kept.
+    fn has_keep_rules(&self) -> bool {
+        self.keep_within.is_some() || !self.timescales().is_empty()
+    }
+
+    /// The timescales with a rule, as the name of the rule, how many periods to keep, and a
+    /// format that names the period a backup was taken in. Periods are in local time, and weeks
+    /// start on Monday.
+    fn timescales(&self) -> Vec<(&'static str, usize, &'static str)> {
+        [
+            ("hourly", self.keep_hourly, "%Y-%m-%d %H:00"),
+            ("daily", self.keep_daily, "%Y-%m-%d"),
+            ("weekly", self.keep_weekly, "%G-W%V"),
+            ("monthly", self.keep_monthly, "%Y-%m"),
+            ("yearly", self.keep_yearly, "%Y"),
+        ]
+        .into_iter()
+        .filter_map(|(rule, count, format)| Some((rule, count?, format)))
+        .collect()
+    }
+
+    /// The backups these rules keep at `now`, by name, with the reason each is kept. A backup
+    /// kept by several rules appears once for each. `backups` must be newest first.
+    pub fn keep(
+        &self,
+        backups: &[&CatalogEntry],
+        now: DateTime<Utc>,
+    ) -> Result<Vec<(String, String)>> {
+        let mut kept = vec![];
+        if let Some(within) = &self.keep_within {
+            let cutoff = offset_by_interval(now, within)?;
+            kept.extend(
+                backups
+                    .iter()
+                    .filter(|backup| backup.created > cutoff)
+                    .map(|backup| (backup.name.clone(), format!("within {}", within))),
+            );
+        }
+        for (rule, count, format) in self.timescales().into_iter() {
+            // The first backup seen in each period is the newest one taken in it
+            let mut last_period = None;
+            let mut periods = 0;
+            for backup in backups.iter() {
+                if periods == count {
+                    break;
+                }
+                let created: DateTime<Local> = backup.created.into();
+                let period = created.format(format).to_string();
+                if last_period.as_ref() == Some(&period) {
+                    continue;
+                }
+                kept.push((backup.name.clone(), format!("{} {}", rule, period)));
+                last_period = Some(period);
+                periods += 1;
+            }
+        }
+        Ok(kept)
+    }
+}
+
+/// Parse a size like "500 GiB" or "2.5GB" into bytes. Units are powers of 1000, or of 1024 if
+/// they have an "i", and a plain number is a number of bytes.
+pub fn parse_size(size: &str) -> Result<u64> {
+    let invalid = || Error::InvalidConfig(format!("invalid size {:?}", size));
+    let pattern = Regex::new(r"^\s*([0-9]+(?:\.[0-9]+)?)\s*([KMGTP]?)(i?)B?\s*$").unwrap();
+    let captures = pattern.captures(size).ok_or_else(invalid)?;
+    let count: f64 = captures[1].parse().map_err(|_| invalid())?;
+    let base: f64 = if captures[3].is_empty() {
+        1000.0
+    } else {
+        1024.0
+    };
+    let power = match &captures[2] {
+        "" => 0,
+        unit => {
+            "KMGTP"
+                .find(unit)
+                .expect("the pattern only matches known units")
+                + 1
+        }
+    };
+    if power == 0 && !captures[3].is_empty() {
+        return Err(invalid());
+    }
+    Ok((count * base.powi(power as i32)) as u64)
+}
+
+/// The total size of the backups named in `names` as stored, or None if the catalog doesn't know
+/// the size of one of them. The catalog only has the size of a repository's manifests, so the
+/// chunks they use are counted too, once however many of them use each chunk.
+fn total_size(
+    config: &Config,
+    backups: &[&CatalogEntry],
+    names: &HashSet<String>,
+) -> Result<Option<u64>> {
+    let chosen: Vec<&CatalogEntry> = backups
+        .iter()
+        .filter(|backup| names.contains(&backup.name))
+        .copied()
+        .collect();
+    let Some(mut size) = chosen.iter().map(|backup| backup.size).sum::<Option<u64>>() else {
+        return Ok(None);
+    };
+    if config.repository {
+        let names: Vec<&str> = chosen.iter().map(|backup| backup.name.as_str()).collect();
+        size += Repository::new(Path::new(&config.dir), &config.gpg_id).chunks_size(&names)?;
+    }
+    Ok(Some(size))
+}
+
+/// Whether to keep one of a config's backups, and why.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Decision {
+    pub name: String,
+    pub keep: bool,
+    pub reasons: Vec<String>,
+}
+
+/// Decide which of a config's backups to keep at `now`, newest first, or None if it has neither
+/// `copies` nor retention rules and so keeps every backup. Limits are applied to what the rules
+/// keep, or to every backup if there are only limits, but never to the newest `min_copies`.
+pub fn decide(
+    name: &str,
+    config: &Config,
+    catalog: &Catalog,
+    now: DateTime<Utc>,
+) -> Result<Option<Vec<Decision>>> {
+    let retention = &config.retention;
+    if config.copies.is_none() && retention.is_empty() {
+        return Ok(None);
+    }
+    let backups = catalog.backups(name);
+    let mut kept: HashMap<String, Vec<String>> = HashMap::new();
+    let mut removed: HashMap<String, String> = HashMap::new();
+    if config.copies.is_none() && !retention.has_keep_rules() {
+        for backup in backups.iter() {
+            kept.entry(backup.name.clone()).or_default();
+        }
+    } else {
+        if let Some(copies) = config.copies {
+            for backup in backups.iter().take(copies) {
+                kept.entry(backup.name.clone())
+                    .or_default()
+                    .push(format!("copies = {}", copies));
+            }
+        }
+        for (backup, reason) in retention.keep(&backups, now)?.into_iter() {
+            kept.entry(backup).or_default().push(reason);
+        }
+    }
+    if let Some(max_age) = &retention.max_age {
+        let cutoff = offset_by_interval(now, max_age)?;
+        for backup in backups.iter().filter(|backup| backup.created <= cutoff) {
+            if kept.remove(&backup.name).is_some() {
+                removed.insert(backup.name.clone(), format!("older than {}", max_age));
+            }
+        }
+    }
+    // Backups that kept ones depend on take up space too
+    let names: HashSet<String> = kept.keys().cloned().collect();
+    for parent in catalog.with_parents(name, &names).difference(&names) {
+        removed.remove(parent);
+        kept.entry(parent.clone())
+            .or_default()
+            .push("a kept incremental backup depends on it".to_string());
+    }
+    if let Some(max_total_size) = &retention.max_total_size {
+        let limit = parse_size(max_total_size)?;
+        let mut fitting = HashSet::new();
+        for backup in backups
+            .iter()
+            .filter(|backup| kept.contains_key(&backup.name))
+        {
+            let mut candidate = fitting.clone();
+            candidate.insert(backup.name.clone());
+            let candidate = catalog.with_parents(name, &candidate);
+            match total_size(config, &backups, &candidate)? {
+                Some(size) if size > limit => break,
+                Some(_) => fitting = candidate,
+                None => {
+                    warn!(
+                        "Not limiting the size of {}, since {} was taken before there was a \
+                         catalog and its size is unknown",
+                        name, backup.name
+                    );
+                    fitting.extend(kept.keys().cloned());
+                    break;
+                }
+            }
+        }
+        kept.retain(|backup, _| {
+            if fitting.contains(backup) {
+                return true;
+            }
+            removed.insert(
+                backup.clone(),
+                format!("would take the total over {}", max_total_size),
+            );
+            false
+        });
+    }
+    // The newest backups are kept whatever the limits say
+    let min_copies = retention.min_copies.unwrap_or(1).max(1);
+    let newest: HashSet<String> = backups
+        .iter()
+        .take(min_copies)
+        .map(|backup| backup.name.clone())
+        .collect();
+    for backup in catalog.with_parents(name, &newest).into_iter() {
+        if removed.remove(&backup).is_some() || !kept.contains_key(&backup) {
+            kept.entry(backup)
+                .or_default()
+                .push(format!("min_copies = {}", min_copies));
+        }
+    }
+    Ok(Some(
+        backups
+            .iter()
+            .map(|backup| match kept.remove(&backup.name) {
+                Some(mut reasons) => {
+                    if reasons.is_empty() {
+                        reasons.push("within the limits".to_string());
+                    }
+                    Decision {
+                        name: backup.name.clone(),
+                        keep: true,
+                        reasons,
+                    }
+                }
+                None => Decision {
+                    name: backup.name.clone(),
+                    keep: false,
+                    reasons: vec![removed
+                        .remove(&backup.name)
+                        .unwrap_or("not kept by copies or any rule".to_string())],
+                },
+            })
+            .collect(),
+    ))
+}
+
+/// The backups of a config that neither `copies` nor its retention rules keep at `now`, or None
+/// if it has neither and so keeps every backup.
+pub fn redundant(
+    name: &str,
+    config: &Config,
+    catalog: &Catalog,
+    now: DateTime<Utc>,
+) -> Result<Option<Vec<String>>> {
+    Ok(decide(name, config, catalog, now)?.map(|decisions| {
+        decisions
+            .into_iter()
+            .filter(|decision| !decision.keep)
+            .map(|decision| decision.name)
+            .collect()
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::{Duration, TimeZone};
+
+    use super::*;
+
+    /// Periods are in local time, so tests are too.
+    fn local(year: i32, month: u32, day: u32, hour: u32) -> DateTime<Utc> {
+        Local
+            .with_ymd_and_hms(year, month, day, hour, 0, 0)
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    fn catalog(times: &[DateTime<Utc>], size: u64) -> Catalog {
+        Catalog {
+            backups: entries(times)
+                .into_iter()
+                .map(|backup| CatalogEntry {
+                    size: Some(size),
+                    ..backup
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn entries(times: &[DateTime<Utc>]) -> Vec<CatalogEntry> {
+        times
+            .iter()
+            .enumerate()
+            .map(|(i, created)| CatalogEntry {
+                name: format!("backup-{}", i),
+                created: *created,
+                config: "config".to_string(),
+                globs: vec![],
+                size: None,
+                sha256: None,
+                gpg_id: None,
+                parent: None,
+            })
+            .collect()
+    }
+
+    fn kept_names(
+        retention: &Retention,
+        backups: &[CatalogEntry],
+        now: DateTime<Utc>,
+    ) -> Vec<String> {
+        let backups: Vec<&CatalogEntry> = backups.iter().collect();
+        let mut names: Vec<String> = retention
+            .keep(&backups, now)
+            .unwrap()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        names.sort();
+        names.dedup();
+        names
+    }
+
+    #[test]
+    fn test_keep_nothing() {
+        let now = local(2000, 1, 10, 12);
+        let backups = entries(&[now]);
+        assert!(Retention::default().is_empty());
+        assert_eq!(
+            kept_names(&Retention::default(), &backups, now),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn test_keep_daily() {
+        // Two backups a day for five days, newest first
+        let now = local(2000, 1, 10, 13);
+        let times: Vec<DateTime<Utc>> = (0..10)
+            .map(|i| now - Duration::hours(i / 2 * 24 + i % 2))
+            .collect();
+        let backups = entries(&times);
+        let retention = Retention {
+            keep_daily: Some(3),
+            ..Default::default()
+        };
+        // The newest backup of each of the last three days
+        assert_eq!(
+            kept_names(&retention, &backups, now),
+            vec!["backup-0", "backup-2", "backup-4"]
+        );
+    }
+
+    #[test]
+    fn test_keep_within_and_monthly() {
+        let now = local(2000, 6, 15, 12);
+        let backups = entries(&[
+            local(2000, 6, 15, 11),
+            local(2000, 6, 14, 12),
+            local(2000, 6, 1, 12),
+            local(2000, 5, 20, 12),
+            local(2000, 5, 10, 12),
+            local(2000, 4, 10, 12),
+        ]);
+        let retention = Retention {
+            keep_within: Some("2 days".to_string()),
+            keep_monthly: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(
+            kept_names(&retention, &backups, now),
+            vec!["backup-0", "backup-1", "backup-3"]
+        );
+        let kept = retention
+            .keep(&backups.iter().collect::<Vec<&CatalogEntry>>(), now)
+            .unwrap();
+        assert!(kept.contains(&("backup-3".to_string(), "monthly 2000-05".to_string())));
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("123").unwrap(), 123);
+        assert_eq!(parse_size("2 KB").unwrap(), 2000);
+        assert_eq!(parse_size("2KiB").unwrap(), 2048);
+        assert_eq!(parse_size("500 GiB").unwrap(), 500 << 30);
+        assert_eq!(parse_size("1.5 M").unwrap(), 1_500_000);
+        assert!(matches!(parse_size("5 iB"), Err(Error::InvalidConfig(_))));
+        assert!(matches!(parse_size("lots"), Err(Error::InvalidConfig(_))));
+    }
+
+    #[test]
+    fn test_redundant_limits() {
+        let now = local(2000, 1, 10, 12);
+        let times: Vec<DateTime<Utc>> = (0..5).map(|i| now - Duration::days(i * 10)).collect();
+        let catalog = catalog(&times, 100);
+        let limited = |retention: Retention| {
+            let config = Config {
+                retention,
+                ..Default::default()
+            };
+            redundant("config", &config, &catalog, now)
+                .unwrap()
+                .unwrap()
+        };
+        assert_eq!(
+            limited(Retention {
+                max_age: Some("25 days".to_string()),
+                ..Default::default()
+            }),
+            vec!["backup-3", "backup-4"]
+        );
+        assert_eq!(
+            limited(Retention {
+                max_total_size: Some("250 B".to_string()),
+                ..Default::default()
+            }),
+            vec!["backup-2", "backup-3", "backup-4"]
+        );
+        // However small the limits, the newest backups are kept
+        assert_eq!(
+            limited(Retention {
+                max_age: Some("1 second".to_string()),
+                min_copies: Some(2),
+                ..Default::default()
+            }),
+            vec!["backup-2", "backup-3", "backup-4"]
+        );
+        assert_eq!(
+            limited(Retention {
+                max_total_size: Some("0".to_string()),
+                ..Default::default()
+            }),
+            vec!["backup-1", "backup-2", "backup-3", "backup-4"]
+        );
+    }
+
+    #[test]
+    fn test_redundant_unknown_size() {
+        let now = local(2000, 1, 10, 12);
+        let times: Vec<DateTime<Utc>> = (0..5).map(|i| now - Duration::days(i * 10)).collect();
+        let mut catalog = catalog(&times, 100);
+        // The oldest two backups were imported, so their sizes are unknown
+        for backup in catalog.backups.iter_mut().skip(3) {
+            backup.size = None;
+        }
+        let limited = |max_total_size: &str| {
+            let config = Config {
+                retention: Retention {
+                    max_total_size: Some(max_total_size.to_string()),
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            redundant("config", &config, &catalog, now)
+                .unwrap()
+                .unwrap()
+        };
+        assert_eq!(limited("250 B"), vec!["backup-2", "backup-3", "backup-4"]);
+        // Whether the unknown backups fit can't be told, so they are kept
+        assert_eq!(limited("1 KB"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_redundant_limits_repository_chunks() {
+        let dir = std::env::temp_dir().join("backer-upper-retention-repository");
+        if dir.exists() {
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+        std::fs::create_dir_all(dir.join("src")).unwrap();
+        let repository = Repository::new(&dir, &None);
+        let now = local(2000, 1, 10, 12);
+        let times: Vec<DateTime<Utc>> = (0..3).map(|i| now - Duration::days(i)).collect();
+        let catalog = catalog(&times, 10);
+        // Every backup has a file of its own, which is much larger than its manifest
+        for backup in catalog.backups.iter() {
+            let file = dir.join("src").join(&backup.name);
+            let data: Vec<u8> = (0..100_000u32).map(|i| (i * 7919 % 251) as u8).collect();
+            std::fs::write(&file, [backup.name.as_bytes(), &data].concat()).unwrap();
+            repository
+                .backup(&[file.display().to_string()], &backup.name)
+                .unwrap();
+        }
+        // Room for the newest two backups and half of the third
+        let newest = repository.chunks_size(&["backup-0", "backup-1"]).unwrap() + 2 * 10;
+        let limit = newest + repository.chunks_size(&["backup-2"]).unwrap() / 2;
+        let config = Config {
+            dir: dir.display().to_string(),
+            repository: true,
+            retention: Retention {
+                max_total_size: Some(limit.to_string()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert_eq!(
+            redundant("config", &config, &catalog, now).unwrap(),
+            Some(vec!["backup-2".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_redundant_limits_rules() {
+        let now = local(2000, 1, 10, 12);
+        let times: Vec<DateTime<Utc>> = (0..5).map(|i| now - Duration::days(i * 10)).collect();
+        let catalog = catalog(&times, 100);
+        let config = Config {
+            copies: Some(4),
+            retention: Retention {
+                max_age: Some("15 days".to_string()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert_eq!(
+            redundant("config", &config, &catalog, now).unwrap(),
+            Some(vec![
+                "backup-2".to_string(),
+                "backup-3".to_string(),
+                "backup-4".to_string()
+            ])
+        );
+        let decisions = decide("config", &config, &catalog, now).unwrap().unwrap();
+        assert_eq!(decisions[0].reasons, vec!["copies = 4"]);
+        assert_eq!(decisions[2].reasons, vec!["older than 15 days"]);
+        let config = Config {
+            retention: Retention::default(),
+            ..config
+        };
+        assert_eq!(
+            redundant("config", &config, &catalog, now).unwrap(),
+            Some(vec!["backup-4".to_string()])
+        );
+        assert_eq!(
+            redundant("config", &Config::default(), &catalog, now).unwrap(),
+            None
+        );
+    }
+}
@@ -0,0 +1,283 @@
+//! Calendar schedules for taking backups: cron expressions saying when backups are due, and
+//! windows saying what time of day they are allowed to run.
+
+use std::str::FromStr;
+
+use chrono::{DateTime, Datelike, Days, Duration, NaiveDate, NaiveTime, TimeZone, Timelike};
+
+use crate::error::{Error, Result};
+
+/// How far ahead to look for the next time a cron expression matches, so that expressions that
+/// never match (like "0 0 31 2 *") don't loop forever. Leap days come around every 4 years, and
+/// the 29th of February falls on every day of the week within 28.
+const SEARCH_YEARS: u64 = 28;
+
+/// A cron expression with five fields: minute, hour, day of month, month and day of week.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cron {
+    minutes: Vec<bool>,
+    hours: Vec<bool>,
+    days: Vec<bool>,
+    months: Vec<bool>,
+    /// Days of the week, from Sunday (0) to Saturday (6).
+    weekdays: Vec<bool>,
+    /// Whether the day of month and day of week fields were "*". If neither was, a day matches
+    /// when either does, like in cron.
+    any_day: bool,
+    any_weekday: bool,
+}
+
+/// Parse a single cron field like "*", "1,15", "9-17" or "*/15", whose values go from `min` to
+/// `max`, into which values match.
+fn parse_field(field: &str, min: u32, max: u32) -> std::result::Result<Vec<bool>, String> {
+    let mut matches = vec![false; max as usize + 1];
+    let value = |value: &str| -> std::result::Result<u32, String> {
+        match value.parse() {
+            Ok(value) if (min..=max).contains(&value) => Ok(value),
+            _ => Err(format!(
+                "{:?} is not a number from {} to {}",
+                value, min, max
+            )),
+        }
+    };
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => match step.parse() {
+                Ok(step) if step > 0 => (range, step),
+                _ => return Err(format!("{:?} is not a valid step", step)),
+            },
+            None => (part, 1),
+        };
+        let (start, end) = match range {
+            "*" => (min, max),
+            range => match range.split_once('-') {
+                Some((start, end)) => (value(start)?, value(end)?),
+                // Like cron, "5/10" means every 10 starting at 5
+                None if step > 1 => (value(range)?, max),
+                None => (value(range)?, value(range)?),
+            },
+        };
+        if start > end {
+            return Err(format!("{:?} is a backwards range", range));
+        }
+        for value in (start..=end).step_by(step) {
+            matches[value as usize] = true;
+        }
+    }
+    Ok(matches)
+}
+
+impl Cron {
+    /// Test if a backup is due on a date.
+    fn matches_date(&self, date: NaiveDate) -> bool {
+        if !self.months[date.month() as usize] {
+            return false;
+        }
+        let day = self.days[date.day() as usize];
+        let weekday = self.weekdays[date.weekday().num_days_from_sunday() as usize];
+        match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        }
+    }
+
+    /// The first time after `time` that the expression matches, in the same time zone. Times
+    /// skipped by a daylight saving change are skipped here too.
+    pub fn next_after<T: TimeZone>(&self, time: &DateTime<T>) -> Option<DateTime<T>> {
+        let zone = time.timezone();
+        let start = time.naive_local().with_second(0)?.with_nanosecond(0)? + Duration::minutes(1);
+        let mut date = start.date();
+        let last = date.checked_add_days(Days::new(SEARCH_YEARS * 366))?;
+        while date <= last {
+            if self.matches_date(date) {
+                for hour in (0..24).filter(|hour| self.hours[*hour as usize]) {
+                    for minute in (0..60).filter(|minute| self.minutes[*minute as usize]) {
+                        let naive = date.and_hms_opt(hour, minute, 0)?;
+                        if naive < start {
+                            continue;
+                        }
+                        if let Some(matched) = zone.from_local_datetime(&naive).earliest() {
+                            return Some(matched);
+                        }
+                    }
+                }
+            }
+            date = date.succ_opt()?;
+        }
+        None
+    }
+}
+
+impl FromStr for Cron {
+    type Err = Error;
+
+    fn from_str(schedule: &str) -> Result<Cron> {
+        let expanded = match schedule.trim() {
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            "@monthly" => "0 0 1 * *",
+            "@weekly" => "0 0 * * 0",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@hourly" => "0 * * * *",
+            schedule => schedule,
+        };
+        let invalid = |reason: String| {
+            Error::InvalidConfig(format!("invalid schedule {:?}: {}", schedule, reason))
+        };
+        let fields: Vec<&str> = expanded.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            return Err(invalid(format!(
+                "expected 5 fields, found {}",
+                fields.len()
+            )));
+        };
+        let mut weekday_matches = parse_field(weekdays, 0, 7).map_err(invalid)?;
+        // Both 0 and 7 are Sunday
+        weekday_matches[0] |= weekday_matches.pop().expect("there are 8 weekdays");
+        Ok(Cron {
+            minutes: parse_field(minutes, 0, 59).map_err(invalid)?,
+            hours: parse_field(hours, 0, 23).map_err(invalid)?,
+            days: parse_field(days, 1, 31).map_err(invalid)?,
+            months: parse_field(months, 1, 12).map_err(invalid)?,
+            weekdays: weekday_matches,
+            any_day: days == "*",
+            any_weekday: weekdays == "*",
+        })
+    }
+}
+
+/// A time of day that backups may run in, like "01:00-05:00". Windows that end before they start
+/// run overnight.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Window {
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl Window {
+    /// Test if a time of day is within the window. The end of the window is not.
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            self.start <= time || time < self.end
+        }
+    }
+
+    /// The first time at or after `time` that is within the window, in the same time zone.
+    pub fn next_open<T: TimeZone>(&self, time: &DateTime<T>) -> Option<DateTime<T>> {
+        if self.contains(time.naive_local().time()) {
+            return Some(time.clone());
+        }
+        let mut date = time.naive_local().date();
+        if time.naive_local().time() >= self.start {
+            date = date.succ_opt()?;
+        }
+        // The start of the window might not exist on the day the clocks go forward
+        (0..2).find_map(|days| {
+            let naive = date.checked_add_days(Days::new(days))?.and_time(self.start);
+            time.timezone().from_local_datetime(&naive).earliest()
+        })
+    }
+}
+
+impl FromStr for Window {
+    type Err = Error;
+
+    fn from_str(window: &str) -> Result<Window> {
+        let invalid =
+            |reason: &str| Error::InvalidConfig(format!("invalid window {:?}: {}", window, reason));
+        let (start, end) = window
+            .split_once('-')
+            .ok_or_else(|| invalid("expected a start and end like \"01:00-05:00\""))?;
+        let time = |time: &str| {
+            NaiveTime::parse_from_str(time.trim(), "%H:%M")
+                .map_err(|_| invalid(&format!("{:?} is not a time like \"01:00\"", time.trim())))
+        };
+        let (start, end) = (time(start)?, time(end)?);
+        if start == end {
+            return Err(invalid("it starts and ends at the same time"));
+        }
+        Ok(Window { start, end })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::Utc;
+
+    use super::*;
+
+    fn at(day: u32, hour: u32, minute: u32) -> DateTime<Utc> {
+        // The 1st of January 2000 was a Saturday
+        Utc.with_ymd_and_hms(2000, 1, day, hour, minute, 0).unwrap()
+    }
+
+    #[test]
+    fn test_cron_next_after() {
+        let daily: Cron = "0 3 * * *".parse().unwrap();
+        assert_eq!(daily.next_after(&at(1, 2, 59)), Some(at(1, 3, 0)));
+        assert_eq!(daily.next_after(&at(1, 3, 0)), Some(at(2, 3, 0)));
+        let quarterly: Cron = "*/15 9-10 * * *".parse().unwrap();
+        assert_eq!(quarterly.next_after(&at(1, 9, 20)), Some(at(1, 9, 30)));
+        assert_eq!(quarterly.next_after(&at(1, 10, 45)), Some(at(2, 9, 0)));
+        let weekdays: Cron = "30 1 * * 1-5".parse().unwrap();
+        assert_eq!(weekdays.next_after(&at(1, 0, 0)), Some(at(3, 1, 30)));
+        let sundays: Cron = "0 0 * * 7".parse().unwrap();
+        assert_eq!(sundays.next_after(&at(1, 0, 0)), Some(at(2, 0, 0)));
+        // When both days are given, either one matching is enough
+        let either: Cron = "0 0 5 * 1".parse().unwrap();
+        assert_eq!(either.next_after(&at(1, 0, 0)), Some(at(3, 0, 0)));
+        assert_eq!(either.next_after(&at(3, 0, 0)), Some(at(5, 0, 0)));
+        let monthly: Cron = "@monthly".parse().unwrap();
+        assert_eq!(
+            monthly.next_after(&at(1, 0, 0)),
+            Some(Utc.with_ymd_and_hms(2000, 2, 1, 0, 0, 0).unwrap())
+        );
+        let leap: Cron = "0 0 29 2 *".parse().unwrap();
+        assert_eq!(
+            leap.next_after(&at(1, 0, 0)),
+            Some(Utc.with_ymd_and_hms(2000, 2, 29, 0, 0, 0).unwrap())
+        );
+        let never: Cron = "0 0 31 2 *".parse().unwrap();
+        assert_eq!(never.next_after(&at(1, 0, 0)), None);
+    }
+
+    #[test]
+    fn test_cron_invalid() {
+        for invalid in [
+            "",
+            "0 3 * *",
+            "60 * * * *",
+            "* * 0 * *",
+            "5-1 * * * *",
+            "*/0 * * * *",
+        ] {
+            assert!(
+                matches!(invalid.parse::<Cron>(), Err(Error::InvalidConfig(_))),
+                "{:?} parsed",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn test_window() {
+        let night: Window = "01:00-05:00".parse().unwrap();
+        assert!(night.contains(NaiveTime::from_hms_opt(1, 0, 0).unwrap()));
+        assert!(!night.contains(NaiveTime::from_hms_opt(5, 0, 0).unwrap()));
+        assert_eq!(night.next_open(&at(1, 2, 0)), Some(at(1, 2, 0)));
+        assert_eq!(night.next_open(&at(1, 0, 30)), Some(at(1, 1, 0)));
+        assert_eq!(night.next_open(&at(1, 12, 0)), Some(at(2, 1, 0)));
+        let overnight: Window = "22:00 - 02:00".parse().unwrap();
+        assert!(overnight.contains(NaiveTime::from_hms_opt(23, 0, 0).unwrap()));
+        assert!(overnight.contains(NaiveTime::from_hms_opt(1, 0, 0).unwrap()));
+        assert!(!overnight.contains(NaiveTime::from_hms_opt(12, 0, 0).unwrap()));
+        assert_eq!(overnight.next_open(&at(1, 12, 0)), Some(at(1, 22, 0)));
+        for invalid in ["01:00", "1am-5am", "01:00-01:00"] {
+            assert!(matches!(
+                invalid.parse::<Window>(),
+                Err(Error::InvalidConfig(_))
+            ));
+        }
+    }
+}
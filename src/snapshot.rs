@@ -0,0 +1,211 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::error::Result;
+use crate::stream::Destination;
+
+/// Added to the name of a backup to get the name of its snapshot.
+pub const SNAPSHOT_SUFFIX: &str = ".snapshot";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    File,
+    Dir,
+    Symlink,
+    Other,
+}
+
+/// The state of a single file when a snapshot was taken.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FileState {
+    pub kind: Kind,
+    pub size: u64,
+    pub mtime: SystemTime,
+    pub mode: u32,
+    /// The SHA-256 of a file's contents or a symlink's target.
+    pub hash: Option<String>,
+}
+
+impl FileState {
+    /// Test if the file looks the same without comparing hashes.
+    fn same_metadata(&self, other: &FileState) -> bool {
+        self.kind == other.kind
+            && self.size == other.size
+            && self.mtime == other.mtime
+            && self.mode == other.mode
+    }
+}
+
+/// The state of every file in a backup. Incremental backups only contain the files that changed
+/// since their parent, so the snapshot is what ties them back to a point in time.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Snapshot {
+    /// The name of the backup this one is an increment on, or None for a full backup.
+    pub parent: Option<String>,
+    /// How many incremental backups there are between this one and the last full backup.
+    pub depth: usize,
+    pub files: BTreeMap<PathBuf, FileState>,
+    /// Files that were in the parent but have since been deleted.
+    pub deleted: Vec<PathBuf>,
+}
+
+fn hash(reader: &mut impl Read) -> Result<String> {
+    let mut hasher = Sha256::new();
+    std::io::copy(reader, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+fn file_state(path: &Path, previous: Option<&FileState>) -> Result<FileState> {
+    let metadata = std::fs::symlink_metadata(path)?;
+    let kind = if metadata.is_file() {
+        Kind::File
+    } else if metadata.is_dir() {
+        Kind::Dir
+    } else if metadata.is_symlink() {
+        Kind::Symlink
+    } else {
+        Kind::Other
+    };
+    let mut state = FileState {
+        kind,
+        size: metadata.len(),
+        mtime: metadata.modified()?,
+        mode: metadata.permissions().mode(),
+        hash: None,
+    };
+    state.hash = match (kind, previous) {
+        (Kind::File | Kind::Symlink, Some(previous)) if previous.same_metadata(&state) => {
+            previous.hash.clone()
+        }
+        (Kind::File, _) => Some(hash(&mut File::open(path)?)?),
+        (Kind::Symlink, _) => Some(hash(&mut std::fs::read_link(path)?.as_os_str().as_bytes())?),
+        _ => None,
+    };
+    Ok(state)
+}
+
+impl Snapshot {
+    /// Record the state of the given paths as a full backup. Files whose metadata has not
+    /// changed since `previous` are not hashed again.
+    pub fn scan(paths: &[PathBuf], previous: Option<&Snapshot>) -> Result<Snapshot> {
+        let mut files = BTreeMap::new();
+        for path in paths.iter() {
+            let previous = previous.and_then(|previous| previous.files.get(path));
+            files.insert(path.clone(), file_state(path, previous)?);
+        }
+        Ok(Snapshot {
+            files,
+            ..Default::default()
+        })
+    }
+
+    /// Turn this into an increment on the backup named `parent_name`.
+    pub fn incremental_on(self, parent_name: &str, parent: &Snapshot) -> Snapshot {
+        let deleted = parent
+            .files
+            .keys()
+            .filter(|path| !self.files.contains_key(*path))
+            .cloned()
+            .collect();
+        Snapshot {
+            parent: Some(parent_name.to_string()),
+            depth: parent.depth + 1,
+            files: self.files,
+            deleted,
+        }
+    }
+
+    /// The files that are new or different since `parent`, parents before children.
+    pub fn changed_since(&self, parent: &Snapshot) -> Vec<PathBuf> {
+        self.files
+            .iter()
+            .filter(|(path, state)| parent.files.get(*path) != Some(state))
+            .map(|(path, _)| path.clone())
+            .collect()
+    }
+
+    /// Load the snapshot stored next to a backup, if there is one.
+    pub fn load(backup: &Destination, gpg_id: &Option<String>) -> Result<Option<Snapshot>> {
+        let sidecar = backup.sidecar(SNAPSHOT_SUFFIX);
+        if !sidecar.exists()? {
+            return Ok(None);
+        }
+        let mut source = sidecar.open(gpg_id)?;
+        let snapshot = Snapshot::read(&mut source);
+        source.finish()?;
+        Ok(Some(snapshot?))
+    }
+
+    pub fn read(reader: impl Read) -> Result<Snapshot> {
+        Ok(serde_json::from_reader(reader)?)
+    }
+
+    pub fn write(&self, writer: impl Write) -> Result<()> {
+        Ok(serde_json::to_writer(writer, self)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("backer-upper-snapshot-{}", name));
+        if dir.exists() {
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a.txt"), "a").unwrap();
+        std::fs::write(dir.join("b.txt"), "b").unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_scan() {
+        let dir = test_dir("scan");
+        let snapshot = Snapshot::scan(&[dir.clone(), dir.join("a.txt")], None).unwrap();
+        assert_eq!(snapshot.files.len(), 2);
+        assert_eq!(snapshot.files[&dir].kind, Kind::Dir);
+        assert_eq!(snapshot.files[&dir].hash, None);
+        assert_eq!(
+            snapshot.files[&dir.join("a.txt")].hash,
+            // echo -n a | sha256sum
+            Some("ca978112ca1bbdcafac231b39a23dc4da786eff8147c4e72b9807785afee48bb".to_string())
+        );
+    }
+
+    #[test]
+    fn test_incremental_on() {
+        let dir = test_dir("incremental");
+        let (a, b, c) = (dir.join("a.txt"), dir.join("b.txt"), dir.join("c.txt"));
+        let parent = Snapshot::scan(&[a.clone(), b.clone()], None).unwrap();
+        std::fs::write(&b, "changed").unwrap();
+        std::fs::write(&c, "c").unwrap();
+        std::fs::remove_file(&a).unwrap();
+        let snapshot = Snapshot::scan(&[b.clone(), c.clone()], Some(&parent))
+            .unwrap()
+            .incremental_on("parent", &parent);
+        assert_eq!(snapshot.parent, Some("parent".to_string()));
+        assert_eq!(snapshot.depth, 1);
+        assert_eq!(snapshot.deleted, vec![a]);
+        assert_eq!(snapshot.changed_since(&parent), vec![b, c]);
+    }
+
+    #[test]
+    fn test_read_write() {
+        let dir = test_dir("read-write");
+        let snapshot = Snapshot::scan(&[dir], None).unwrap();
+        let mut buffer = vec![];
+        snapshot.write(&mut buffer).unwrap();
+        assert_eq!(Snapshot::read(buffer.as_slice()).unwrap(), snapshot);
+    }
+}
@@ -0,0 +1,626 @@
+//! Plumbing for streaming archives through `gpg` and `ssh` without touching the disk.
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+
+use log::{debug, warn};
+use sha2::{Digest, Sha256};
+
+use crate::error::{CommandError, Error, Result};
+use crate::utils::{run, shell_quote};
+
+/// Turns the failure of a child process into an error.
+type OnError = Box<dyn FnOnce(CommandError) -> Error + Send>;
+
+/// Wait for a child process to exit, returning an error if it failed.
+fn wait(command: &Command, child: Child, on_error: OnError) -> Result<()> {
+    let output = match child.wait_with_output() {
+        Ok(output) => output,
+        Err(e) => return Err(on_error(CommandError::new(command, None, &e.to_string()))),
+    };
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(on_error(CommandError::new(
+            command,
+            output.status.code(),
+            &stderr,
+        )));
+    }
+    Ok(())
+}
+
+fn spawn(command: &mut Command, on_error: OnError) -> Result<(Child, OnError)> {
+    debug!("Spawning {:?}", command);
+    match command.stderr(Stdio::piped()).spawn() {
+        Ok(child) => Ok((child, on_error)),
+        Err(e) => Err(on_error(CommandError::new(command, None, &e.to_string()))),
+    }
+}
+
+/// The end of a stream of bytes. Writing has only succeeded once `finish` has returned.
+pub trait Sink: Write + Send {
+    fn finish(self: Box<Self>) -> Result<()>;
+}
+
+impl Sink for BufWriter<File> {
+    fn finish(self: Box<Self>) -> Result<()> {
+        let file = self.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        Ok(())
+    }
+}
+
+impl Sink for Vec<u8> {
+    fn finish(self: Box<Self>) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Write a stream into the stdin of a child process.
+pub struct ChildWriter {
+    command: Command,
+    child: Child,
+    stdin: ChildStdin,
+    on_error: OnError,
+}
+
+impl ChildWriter {
+    pub fn new(mut command: Command, on_error: OnError) -> Result<ChildWriter> {
+        let (mut child, on_error) = spawn(command.stdin(Stdio::piped()), on_error)?;
+        let stdin = child.stdin.take().expect("stdin is piped");
+        Ok(ChildWriter {
+            command,
+            child,
+            stdin,
+            on_error,
+        })
+    }
+}
+
+impl Write for ChildWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.stdin.write(buf)
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.stdin.flush()
+    }
+}
+
+impl Sink for ChildWriter {
+    fn finish(self: Box<Self>) -> Result<()> {
+        let ChildWriter {
+            command,
+            child,
+            stdin,
+            on_error,
+        } = *self;
+        drop(stdin);
+        wait(&command, child, on_error)
+    }
+}
+
+/// Pipe a stream through a child process and into another sink.
+pub struct Filter {
+    inner: ChildWriter,
+    copier: JoinHandle<(Box<dyn Sink>, std::io::Result<u64>)>,
+}
+
+impl Filter {
+    pub fn new(mut command: Command, on_error: OnError, mut sink: Box<dyn Sink>) -> Result<Filter> {
+        command.stdout(Stdio::piped());
+        let mut inner = ChildWriter::new(command, on_error)?;
+        let mut stdout = inner.child.stdout.take().expect("stdout is piped");
+        let copier = std::thread::spawn(move || {
+            let copied = std::io::copy(&mut stdout, &mut sink);
+            (sink, copied)
+        });
+        Ok(Filter { inner, copier })
+    }
+}
+
+impl Write for Filter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.inner.write(buf)
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Sink for Filter {
+    fn finish(self: Box<Self>) -> Result<()> {
+        let Filter { inner, copier } = *self;
+        // The child won't exit until its stdin is closed, so finish it first
+        let finished = Box::new(inner).finish();
+        let (sink, copied) = copier.join().expect("the copier thread panicked");
+        finished?;
+        copied?;
+        sink.finish()
+    }
+}
+
+/// Encrypt everything written to the returned sink for `gpg_id` and pass it on to `sink`.
+pub fn encrypt(gpg_id: &str, sink: Box<dyn Sink>) -> Result<Box<dyn Sink>> {
+    let mut command = Command::new("gpg");
+    command.args(["--encrypt", "--recipient", gpg_id]);
+    Ok(Box::new(Filter::new(command, Box::new(Error::Gpg), sink)?))
+}
+
+/// The size and SHA-256 of a stream, as it was stored at its destination.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Written {
+    pub size: u64,
+    pub sha256: String,
+}
+
+/// Pass a stream on to another sink, measuring it on the way. The measurements are only
+/// available once the sink has been finished.
+struct Measure {
+    inner: Box<dyn Sink>,
+    size: u64,
+    hasher: Sha256,
+    written: Arc<Mutex<Option<Written>>>,
+}
+
+impl Write for Measure {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.size += n as u64;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Sink for Measure {
+    fn finish(self: Box<Self>) -> Result<()> {
+        let Measure {
+            inner,
+            size,
+            hasher,
+            written,
+        } = *self;
+        inner.finish()?;
+        *written.lock().expect("the lock is never poisoned") = Some(Written {
+            size,
+            sha256: format!("{:x}", hasher.finalize()),
+        });
+        Ok(())
+    }
+}
+
+/// Stream whatever `write` produces to the destination, encrypting it if necessary.
+pub fn write_to(
+    destination: &Destination,
+    gpg_id: &Option<String>,
+    write: impl FnOnce(&mut dyn Write) -> Result<()>,
+) -> Result<Written> {
+    let measured = Arc::new(Mutex::new(None));
+    let mut sink: Box<dyn Sink> = Box::new(Measure {
+        inner: destination.create()?,
+        size: 0,
+        hasher: Sha256::new(),
+        written: measured.clone(),
+    });
+    if let Some(gpg_id) = gpg_id {
+        sink = encrypt(gpg_id, sink)?;
+    }
+    let written = write(&mut sink);
+    // If gpg or ssh died, the reason they give is more useful than a broken pipe
+    sink.finish()?;
+    written?;
+    let measured = measured.lock().expect("the lock is never poisoned").take();
+    Ok(measured.expect("the sink was finished"))
+}
+
+/// Added to the name of a destination to get the name it is written to before it is complete.
+pub const PARTIAL_SUFFIX: &str = ".partial";
+
+/// Stream whatever `write` produces to a temporary name next to the destination, and only move
+/// it into place once it is complete, so an interrupted write never looks like a backup. A remote
+/// upload is measured where it is stored before it is moved, so one that did not arrive intact is
+/// an error too.
+pub fn store(
+    destination: &Destination,
+    gpg_id: &Option<String>,
+    write: impl FnOnce(&mut dyn Write) -> Result<()>,
+) -> Result<Written> {
+    let partial = destination.sidecar(PARTIAL_SUFFIX);
+    let stored = write_to(&partial, gpg_id, write).and_then(|written| {
+        if let Destination::Remote { host, .. } = destination {
+            let stored = partial.measure()?;
+            if stored != written {
+                return Err(Error::Corrupt(
+                    destination.path().to_path_buf(),
+                    format!(
+                        "uploaded {} bytes with SHA-256 {}, but {} has {} bytes with SHA-256 {}",
+                        written.size, written.sha256, host, stored.size, stored.sha256
+                    ),
+                ));
+            }
+        }
+        Ok(written)
+    });
+    match stored {
+        Ok(written) => {
+            partial.rename(destination.path())?;
+            Ok(written)
+        }
+        Err(err) => {
+            if let Err(err) = partial.remove() {
+                warn!("Failed to remove {:?}: {}", partial.path(), err);
+            }
+            Err(err)
+        }
+    }
+}
+
+/// The start of a stream of bytes. Reading has only succeeded once `finish` has returned.
+pub trait Source: Read + Send {
+    fn finish(self: Box<Self>) -> Result<()>;
+}
+
+impl Source for BufReader<File> {
+    fn finish(self: Box<Self>) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl Source for std::io::Cursor<Vec<u8>> {
+    fn finish(self: Box<Self>) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Read a stream from the stdout of a child process.
+pub struct ChildReader {
+    command: Command,
+    child: Child,
+    stdout: ChildStdout,
+    on_error: OnError,
+}
+
+impl ChildReader {
+    pub fn new(mut command: Command, on_error: OnError) -> Result<ChildReader> {
+        let (mut child, on_error) = spawn(command.stdout(Stdio::piped()), on_error)?;
+        let stdout = child.stdout.take().expect("stdout is piped");
+        Ok(ChildReader {
+            command,
+            child,
+            stdout,
+            on_error,
+        })
+    }
+}
+
+impl Read for ChildReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.stdout.read(buf)
+    }
+}
+
+impl Source for ChildReader {
+    fn finish(self: Box<Self>) -> Result<()> {
+        let ChildReader {
+            command,
+            child,
+            mut stdout,
+            on_error,
+        } = *self;
+        // Drain anything that wasn't read so the child doesn't block on a full pipe
+        std::io::copy(&mut stdout, &mut std::io::sink())?;
+        wait(&command, child, on_error)
+    }
+}
+
+/// Pipe another source through a child process.
+pub struct SourceFilter {
+    inner: ChildReader,
+    feeder: JoinHandle<(Box<dyn Source>, std::io::Result<u64>)>,
+}
+
+impl SourceFilter {
+    pub fn new(
+        mut command: Command,
+        on_error: OnError,
+        mut source: Box<dyn Source>,
+    ) -> Result<SourceFilter> {
+        command.stdin(Stdio::piped());
+        let mut inner = ChildReader::new(command, on_error)?;
+        let mut stdin = inner.child.stdin.take().expect("stdin is piped");
+        let feeder = std::thread::spawn(move || {
+            let copied = std::io::copy(&mut source, &mut stdin);
+            (source, copied)
+        });
+        Ok(SourceFilter { inner, feeder })
+    }
+}
+
+impl Read for SourceFilter {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl Source for SourceFilter {
+    fn finish(self: Box<Self>) -> Result<()> {
+        let SourceFilter { inner, feeder } = *self;
+        let finished = Box::new(inner).finish();
+        let (source, copied) = feeder.join().expect("the feeder thread panicked");
+        finished?;
+        source.finish()?;
+        copied?;
+        Ok(())
+    }
+}
+
+/// Decrypt a source that was encrypted with gpg.
+pub fn decrypt(source: Box<dyn Source>) -> Result<Box<dyn Source>> {
+    let mut command = Command::new("gpg");
+    command.arg("--decrypt");
+    Ok(Box::new(SourceFilter::new(
+        command,
+        Box::new(Error::Gpg),
+        source,
+    )?))
+}
+
+/// Open a backup for reading, decrypting it if it was encrypted.
+pub fn open(backup: &Path, gpg_id: &Option<String>) -> Result<Box<dyn Source>> {
+    Destination::Local(backup.to_path_buf()).open(gpg_id)
+}
+
+/// Where a backup is written to.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Destination {
+    Local(PathBuf),
+    Remote { host: String, path: PathBuf },
+}
+
+impl Destination {
+    pub fn new(host: &Option<String>, path: PathBuf) -> Destination {
+        match host {
+            Some(host) => Destination::Remote {
+                host: host.clone(),
+                path,
+            },
+            None => Destination::Local(path),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        match self {
+            Destination::Local(path) => path,
+            Destination::Remote { path, .. } => path,
+        }
+    }
+
+    /// The destination of a file stored next to this one, named by adding `suffix`.
+    pub fn sidecar(&self, suffix: &str) -> Destination {
+        let mut path = self.path().as_os_str().to_owned();
+        path.push(suffix);
+        match self {
+            Destination::Local(_) => Destination::Local(path.into()),
+            Destination::Remote { host, .. } => Destination::Remote {
+                host: host.clone(),
+                path: path.into(),
+            },
+        }
+    }
+
+    /// Open the destination for reading, decrypting it if it was encrypted. Remote files are
+    /// streamed over `ssh`.
+    pub fn open(&self, gpg_id: &Option<String>) -> Result<Box<dyn Source>> {
+        let source: Box<dyn Source> = match self {
+            Destination::Local(path) => Box::new(BufReader::new(File::open(path)?)),
+            Destination::Remote { host, path } => {
+                let mut command = Command::new("ssh");
+                command.arg(host).arg(format!("cat {}", shell_quote(path)));
+                Box::new(ChildReader::new(command, Box::new(Error::remote(host)))?)
+            }
+        };
+        if gpg_id.is_some() {
+            decrypt(source)
+        } else {
+            Ok(source)
+        }
+    }
+
+    /// Test if the destination exists.
+    pub fn exists(&self) -> Result<bool> {
+        match self {
+            Destination::Local(path) => Ok(path.exists()),
+            Destination::Remote { host, path } => {
+                let output = run(Command::new("ssh")
+                    .arg(host)
+                    .arg(format!("test -e {} && echo yes || true", shell_quote(path))))
+                .map_err(Error::remote(host))?;
+                Ok(output.trim() == "yes")
+            }
+        }
+    }
+
+    /// Measure the destination as it is stored. Remote files are hashed on the remote host.
+    pub fn measure(&self) -> Result<Written> {
+        match self {
+            Destination::Local(path) => {
+                let mut hasher = Sha256::new();
+                let size = std::io::copy(&mut File::open(path)?, &mut hasher)?;
+                Ok(Written {
+                    size,
+                    sha256: format!("{:x}", hasher.finalize()),
+                })
+            }
+            Destination::Remote { host, path } => {
+                let output = run(Command::new("ssh").arg(host).arg(format!(
+                    "stat -c %s {0} && sha256sum < {0}",
+                    shell_quote(path)
+                )))
+                .map_err(Error::remote(host))?;
+                let mut words = output.split_whitespace();
+                match (words.next().map(str::parse), words.next()) {
+                    (Some(Ok(size)), Some(sha256)) => Ok(Written {
+                        size,
+                        sha256: sha256.to_string(),
+                    }),
+                    _ => Err(Error::Corrupt(
+                        path.clone(),
+                        format!("could not measure it on {}: {:?}", host, output),
+                    )),
+                }
+            }
+        }
+    }
+
+    /// Move the destination to `to`, which must be on the same host.
+    pub fn rename(&self, to: &Path) -> Result<()> {
+        match self {
+            Destination::Local(path) => Ok(std::fs::rename(path, to)?),
+            Destination::Remote { host, path } => {
+                run(Command::new("ssh").arg(host).arg(format!(
+                    "mv -f {} {}",
+                    shell_quote(path),
+                    shell_quote(to)
+                )))
+                .map_err(Error::remote(host))?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Remove the destination if it exists.
+    pub fn remove(&self) -> Result<()> {
+        match self {
+            Destination::Local(path) => match std::fs::remove_file(path) {
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+                removed => Ok(removed?),
+            },
+            Destination::Remote { host, path } => {
+                run(Command::new("ssh")
+                    .arg(host)
+                    .arg(format!("rm -f {}", shell_quote(path))))
+                .map_err(Error::remote(host))?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Open the destination for writing. Remote files are streamed over `ssh`.
+    pub fn create(&self) -> Result<Box<dyn Sink>> {
+        match self {
+            Destination::Local(path) => Ok(Box::new(BufWriter::new(File::create(path)?))),
+            Destination::Remote { host, path } => {
+                let mut command = Command::new("ssh");
+                command
+                    .arg(host)
+                    .arg(format!("cat > {}", shell_quote(path)));
+                Ok(Box::new(ChildWriter::new(
+                    command,
+                    Box::new(Error::remote(host)),
+                )?))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A sink that shares its buffer, so it can be inspected after being boxed and finished.
+    #[derive(Clone, Default)]
+    struct SharedSink(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl Write for SharedSink {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Sink for SharedSink {
+        fn finish(self: Box<Self>) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_filter() {
+        let output = SharedSink::default();
+        let mut command = Command::new("tr");
+        command.args(["a-z", "A-Z"]);
+        let mut filter = Box::new(
+            Filter::new(command, Box::new(Error::Command), Box::new(output.clone())).unwrap(),
+        );
+        filter.write_all(b"hello").unwrap();
+        filter.finish().unwrap();
+        assert_eq!(output.0.lock().unwrap().as_slice(), b"HELLO");
+    }
+
+    #[test]
+    fn test_filter_failure() {
+        let command = Command::new("false");
+        let filter =
+            Box::new(Filter::new(command, Box::new(Error::Gpg), Box::new(vec![])).unwrap());
+        assert!(matches!(filter.finish(), Err(Error::Gpg(_))));
+    }
+
+    #[test]
+    fn test_source_filter() {
+        let mut command = Command::new("tr");
+        command.args(["a-z", "A-Z"]);
+        let source: Box<dyn Source> = Box::new(std::io::Cursor::new(b"hello".to_vec()));
+        let mut filter =
+            Box::new(SourceFilter::new(command, Box::new(Error::Command), source).unwrap());
+        let mut output = String::new();
+        filter.read_to_string(&mut output).unwrap();
+        filter.finish().unwrap();
+        assert_eq!(output, "HELLO");
+    }
+
+    #[test]
+    fn test_sidecar() {
+        assert_eq!(
+            Destination::Local("/a/b.tar.gz".into()).sidecar(".snapshot"),
+            Destination::Local("/a/b.tar.gz.snapshot".into())
+        );
+    }
+
+    #[test]
+    fn test_write_to_measures() {
+        let path = std::env::temp_dir().join("backer-upper-stream-measure");
+        let written = write_to(&Destination::Local(path.clone()), &None, |sink| {
+            Ok(sink.write_all(b"a")?)
+        })
+        .unwrap();
+        assert_eq!(
+            written,
+            Written {
+                size: 1,
+                // echo -n a | sha256sum
+                sha256: "ca978112ca1bbdcafac231b39a23dc4da786eff8147c4e72b9807785afee48bb"
+                    .to_string()
+            }
+        );
+        assert_eq!(std::fs::read(path).unwrap(), b"a");
+    }
+
+    #[test]
+    fn test_child_reader() {
+        let mut command = Command::new("echo");
+        command.arg("hello");
+        let mut reader = Box::new(ChildReader::new(command, Box::new(Error::Command)).unwrap());
+        let mut output = String::new();
+        reader.read_to_string(&mut output).unwrap();
+        reader.finish().unwrap();
+        assert_eq!(output, "hello\n");
+    }
+}
@@ -1,15 +1,38 @@
-use log::{debug, error};
+use log::{debug, warn};
+use std::io::Write;
+use std::path::Path;
 use std::process::Command;
 
-pub fn run(command: &mut Command) -> String {
+use crate::error::CommandError;
+
+/// Run a command to completion and return its stdout.
+///
+/// Anything written to stderr is logged, and a nonzero exit status is returned as an error.
+pub fn run(command: &mut Command) -> Result<String, CommandError> {
     debug!("Running {:?}", command);
-    let output = command.output().unwrap();
-    let err = String::from_utf8(output.stderr).unwrap();
-    if !err.is_empty() {
-        error!("{}", err);
-    }
+    let output = command
+        .output()
+        .map_err(|e| CommandError::new(command, None, &e.to_string()))?;
+    let err = String::from_utf8_lossy(&output.stderr);
     if output.status.code() != Some(0) {
-        panic!("error running command {:?}", output.status.code());
+        return Err(CommandError::new(command, output.status.code(), &err));
+    }
+    if !err.is_empty() {
+        warn!("{}", err.trim());
     }
-    String::from_utf8(output.stdout).unwrap()
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Quote a path so that it can be safely interpolated into a remote shell command.
+pub fn shell_quote(path: &Path) -> String {
+    format!("'{}'", path.to_string_lossy().replace('\'', "'\\''"))
+}
+
+/// Ask a yes or no question on the terminal. Anything but a yes is a no.
+pub fn confirm(question: &str) -> std::io::Result<bool> {
+    eprint!("{} [y/N] ", question);
+    std::io::stderr().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
 }
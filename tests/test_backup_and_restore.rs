@@ -1,12 +1,26 @@
 use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::sync::OnceLock;
+use std::time::Duration;
 
 use serial_test::serial;
 
+use backer_upper::archive::{Action, Overwrite, Target};
+use backer_upper::catalog::Catalog;
 use backer_upper::commands::backup::backup;
-use backer_upper::commands::restore::restore;
-use backer_upper::commands::sync::sync_config;
-use backer_upper::config::Config;
+use backer_upper::commands::contents::list_contents;
+use backer_upper::commands::list::list_config;
+use backer_upper::commands::prune::{prune, prune_config};
+use backer_upper::commands::restore::{plan_restore, restore, restore_to, Step};
+use backer_upper::commands::status::{status_config, State};
+use backer_upper::commands::sync::{plan_config, sync, sync_config, Outcome};
+use backer_upper::commands::verify::{verify, verify_config};
+use backer_upper::config::{read_config_file, write_config_file, Config, ConfigCollection};
+use backer_upper::error::Error;
+use backer_upper::lock::{Lock, LOCK_FILE};
+use backer_upper::retention::Retention;
+use backer_upper::snapshot::{Kind, Snapshot};
+use backer_upper::stream::Destination;
 use backer_upper::utils::run;
 
 fn root() -> PathBuf {
@@ -16,25 +30,19 @@ fn test_file(root: &Path, name: &str) {
     std::fs::write(root.join(name), name).unwrap();
 }
 
-static mut GENKEYFILE: Option<PathBuf> = None;
+static GENKEYFILE: OnceLock<PathBuf> = OnceLock::new();
 
 fn setup_test_env() {
     // Enable logging
     #![allow(unused_must_use)]
     env_logger::try_init();
     // Locate the genkey file before changing cwd
-    let genkeyfile;
-    unsafe {
-        if GENKEYFILE.is_none() {
-            GENKEYFILE = Some(
-                std::env::current_dir()
-                    .unwrap()
-                    .join("tests")
-                    .join("genkey"),
-            );
-        }
-        genkeyfile = GENKEYFILE.clone().unwrap();
-    }
+    let genkeyfile = GENKEYFILE.get_or_init(|| {
+        std::env::current_dir()
+            .unwrap()
+            .join("tests")
+            .join("genkey")
+    });
 
     // Delete and recreate the test data dir
     let root = &root();
@@ -61,7 +69,12 @@ fn setup_test_env() {
         "--generate-key",
         "--batch",
         genkeyfile.as_os_str().to_str().unwrap(),
-    ]));
+    ]))
+    .unwrap();
+}
+/// Backup names only have a resolution of one second, so wait before taking another.
+fn wait_for_next_name() {
+    std::thread::sleep(Duration::from_secs(1));
 }
 fn sanitize_test_env() {
     let root = &root();
@@ -85,7 +98,7 @@ fn assert_no_files(files: &[&str]) {
 
 #[test]
 #[serial]
-fn test_backup_restore_glob_star() -> Result<(), clap::error::Error> {
+fn test_backup_restore_glob_star() -> Result<(), Error> {
     setup_test_env();
     // backup all files
     backup(&["*".to_string()], Path::new("/tmp/backup.tar.gz"), &None)?;
@@ -98,7 +111,7 @@ fn test_backup_restore_glob_star() -> Result<(), clap::error::Error> {
 
 #[test]
 #[serial]
-fn test_backup_restore_single_file() -> Result<(), clap::error::Error> {
+fn test_backup_restore_single_file() -> Result<(), Error> {
     setup_test_env();
     // backup a single file
     backup(
@@ -116,7 +129,7 @@ fn test_backup_restore_single_file() -> Result<(), clap::error::Error> {
 
 #[test]
 #[serial]
-fn test_backup_restore_single_file_from_glob_star() -> Result<(), clap::error::Error> {
+fn test_backup_restore_single_file_from_glob_star() -> Result<(), Error> {
     setup_test_env();
     // backup all files
     backup(&["*".to_string()], Path::new("/tmp/backup.tar.gz"), &None)?;
@@ -134,7 +147,7 @@ fn test_backup_restore_single_file_from_glob_star() -> Result<(), clap::error::E
 
 #[test]
 #[serial]
-fn test_backup_restore_explicit_output() -> Result<(), clap::error::Error> {
+fn test_backup_restore_explicit_output() -> Result<(), Error> {
     setup_test_env();
     // backup a single file
     backup(
@@ -156,7 +169,7 @@ fn test_backup_restore_explicit_output() -> Result<(), clap::error::Error> {
 
 #[test]
 #[serial]
-fn test_backup_restore_encrypted() -> Result<(), clap::error::Error> {
+fn test_backup_restore_encrypted() -> Result<(), Error> {
     setup_test_env();
     // backup all files
     backup(
@@ -177,7 +190,7 @@ fn test_backup_restore_encrypted() -> Result<(), clap::error::Error> {
 
 #[test]
 #[serial]
-fn test_backup_restore_encrypted_with_output() -> Result<(), clap::error::Error> {
+fn test_backup_restore_encrypted_with_output() -> Result<(), Error> {
     setup_test_env();
     // backup all files
     backup(
@@ -198,21 +211,21 @@ fn test_backup_restore_encrypted_with_output() -> Result<(), clap::error::Error>
 
 #[test]
 #[serial]
-fn test_sync_and_restore() -> Result<(), clap::error::Error> {
+fn test_sync_and_restore() -> Result<(), Error> {
     setup_test_env();
     std::fs::create_dir_all("/tmp/backer-upper-sync/").unwrap();
     let backup = sync_config(
-        "test",
+        "sync_and_restore",
         &Config {
             globs: vec!["/tmp/backer-upper/*".to_string()],
             gpg_id: Some("test@chiquit.ooo".to_string()),
-            host: None,
             dir: "/tmp/backer-upper-sync/".to_string(),
             format: "test_sync_%Y-%m-%d_%H:%M:%S.tar.gz.gpg".to_string(),
             interval: "1 second".to_string(),
-            copies: None,
+            ..Default::default()
         },
     )?
+    .into_backup()
     .unwrap();
     sanitize_test_env();
     restore(&backup, &None, &Some("test@chiquit.ooo".to_string()))?;
@@ -222,73 +235,929 @@ fn test_sync_and_restore() -> Result<(), clap::error::Error> {
 
 #[test]
 #[serial]
-fn test_sync_redundant() -> Result<(), clap::error::Error> {
+fn test_sync_redundant() -> Result<(), Error> {
     setup_test_env();
     std::fs::create_dir_all("/tmp/backer-upper-sync/").unwrap();
     let config = Config {
         globs: vec!["/tmp/backer-upper/*".to_string()],
         gpg_id: Some("test@chiquit.ooo".to_string()),
-        host: None,
         dir: "/tmp/backer-upper-sync/".to_string(),
         format: "test_redundant_sync_%Y-%m-%d_%H:%M:%S.tar.gz.gpg".to_string(),
         // This test will fail if run multiple times within two seconds
         interval: "2 seconds".to_string(),
-        copies: None,
+        ..Default::default()
     };
-    let backup = sync_config("test", &config)?.unwrap();
+    let backup = sync_config("sync_redundant", &config)?
+        .into_backup()
+        .unwrap();
     assert!(backup.exists());
     // Sync again, this one shouldn't need a new backup
-    assert_eq!(sync_config("test", &config)?, None);
+    assert!(matches!(
+        sync_config("sync_redundant", &config)?,
+        Outcome::Skipped { .. }
+    ));
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_sync_catalog() -> Result<(), Error> {
+    setup_test_env();
+    let dir = Path::new("/tmp/backer-upper-catalog/");
+    if dir.exists() {
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+    std::fs::create_dir_all(dir).unwrap();
+    let config = Config {
+        globs: vec!["/tmp/backer-upper/*".to_string()],
+        dir: dir.to_str().unwrap().to_string(),
+        format: "test_catalog_%Y-%m-%d_%H:%M:%S.tar.gz".to_string(),
+        interval: "1 hour".to_string(),
+        ..Default::default()
+    };
+    let backup = sync_config("sync_catalog", &config)?.into_backup().unwrap();
+    let catalog = Catalog::load(&Catalog::destination(&None, dir))?;
+    let entry = &catalog.backups("sync_catalog")[0];
+    assert_eq!(entry.name, backup.file_name().unwrap().to_str().unwrap());
+    assert_eq!(entry.size, Some(std::fs::metadata(&backup).unwrap().len()));
+    let sha256sum = run(Command::new("sha256sum").arg(&backup)).unwrap();
+    assert_eq!(entry.sha256.as_deref(), sha256sum.split(' ').next());
+
+    // Backups taken before there was a catalog are found by their names and mtimes. The mtime
+    // can be a little before the name if the filesystem clock is coarse, so make sure it isn't.
+    std::fs::remove_file(dir.join("catalog.json")).unwrap();
+    run(Command::new("touch").arg(&backup)).unwrap();
+    assert!(matches!(
+        sync_config("sync_catalog", &config)?,
+        Outcome::Skipped { .. }
+    ));
+    // Once they are in the catalog, their mtimes no longer matter
+    run(Command::new("touch")
+        .args(["-d", "2000-01-01"])
+        .arg(&backup))
+    .unwrap();
+    assert!(matches!(
+        sync_config("sync_catalog", &config)?,
+        Outcome::Skipped { .. }
+    ));
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_list_config() -> Result<(), Error> {
+    setup_test_env();
+    std::fs::create_dir_all("/tmp/backer-upper-sync/").unwrap();
+    let config = Config {
+        globs: vec!["/tmp/backer-upper/*".to_string()],
+        dir: "/tmp/backer-upper-sync/".to_string(),
+        format: "test_list_%Y-%m-%d_%H:%M:%S.tar.gz".to_string(),
+        interval: "0 seconds".to_string(),
+        copies: Some(2),
+        ..Default::default()
+    };
+    let backup_1 = sync_config("list_config", &config)?.into_backup().unwrap();
+    wait_for_next_name();
+    let backup_2 = sync_config("list_config", &config)?.into_backup().unwrap();
+    let listings = list_config("list_config", &config)?;
+    let names: Vec<&str> = listings.iter().map(|l| l.name.as_str()).collect();
+    assert_eq!(
+        names,
+        vec![
+            backup_2.file_name().unwrap().to_str().unwrap(),
+            backup_1.file_name().unwrap().to_str().unwrap()
+        ]
+    );
+    // The oldest backup goes once the next one is taken
+    assert!(!listings[0].prune);
+    assert!(listings[1].prune);
+    assert_eq!(
+        listings[0].size,
+        Some(std::fs::metadata(&backup_2).unwrap().len())
+    );
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_sync_dry_run() -> Result<(), Error> {
+    setup_test_env();
+    let dir = Path::new("/tmp/backer-upper-dry-run/");
+    if dir.exists() {
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+    std::fs::create_dir_all(dir).unwrap();
+    let config = Config {
+        globs: vec!["/tmp/backer-upper/*".to_string()],
+        dir: dir.to_str().unwrap().to_string(),
+        format: "test_dry_run_%Y-%m-%d_%H:%M:%S.tar.gz".to_string(),
+        interval: "0 seconds".to_string(),
+        copies: Some(1),
+        ..Default::default()
+    };
+    let Outcome::Planned { backup, files, .. } = plan_config("sync_dry_run", &config)? else {
+        panic!("a backup should be due");
+    };
+    assert_eq!(files.len(), 3);
+    assert!(!backup.exists());
+    assert_eq!(std::fs::read_dir(dir).unwrap().count(), 0);
+
+    let existing = sync_config("sync_dry_run", &config)?.into_backup().unwrap();
+    let Outcome::Planned { pruned, .. } = plan_config("sync_dry_run", &config)? else {
+        panic!("a backup should be due");
+    };
+    assert_eq!(pruned, vec![existing.clone()]);
+    assert!(existing.exists());
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_verify() -> Result<(), Error> {
+    setup_test_env();
+    let dir = Path::new("/tmp/backer-upper-verify/");
+    if dir.exists() {
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+    std::fs::create_dir_all(dir).unwrap();
+    let config = Config {
+        globs: vec!["/tmp/backer-upper/*".to_string()],
+        gpg_id: Some("test@chiquit.ooo".to_string()),
+        dir: dir.to_str().unwrap().to_string(),
+        format: "test_verify_%Y-%m-%d_%H:%M:%S.tar.gz.gpg".to_string(),
+        interval: "0 seconds".to_string(),
+        ..Default::default()
+    };
+    let synced = sync_config("verify", &config)?.into_backup().unwrap();
+    let results = verify_config("verify", &config)?;
+    assert_eq!(results.len(), 1);
+    assert!(results[0].1.is_ok());
+
+    // The catalog notices any change to the stored backup
+    let mut contents = std::fs::read(&synced).unwrap();
+    let last = contents.len() - 1;
+    contents[last] ^= 0xff;
+    std::fs::write(&synced, &contents).unwrap();
+    let results = verify_config("verify", &config)?;
+    assert!(matches!(results[0].1, Err(Error::Corrupt(_, _))));
+
+    // Without a catalog, a truncated archive is still noticed
+    let plain = Path::new("/tmp/backup.tar.gz");
+    backup(&["/tmp/backer-upper/*".to_string()], plain, &None)?;
+    verify(plain, &None)?;
+    let contents = std::fs::read(plain).unwrap();
+    std::fs::write(plain, &contents[..contents.len() / 2]).unwrap();
+    assert!(matches!(verify(plain, &None), Err(Error::Corrupt(_, _))));
     Ok(())
 }
 
 #[test]
 #[serial]
-fn test_sync_one_copy() -> Result<(), clap::error::Error> {
+fn test_sync_one_copy() -> Result<(), Error> {
     setup_test_env();
     std::fs::create_dir_all("/tmp/backer-upper-sync/").unwrap();
     let config = Config {
         globs: vec!["/tmp/backer-upper/*".to_string()],
         gpg_id: Some("test@chiquit.ooo".to_string()),
-        host: None,
         dir: "/tmp/backer-upper-sync/".to_string(),
         format: "test_sync_copies_%Y-%m-%d_%H:%M:%S.tar.gz.gpg".to_string(),
         // Always run
         interval: "0 seconds".to_string(),
         copies: Some(1),
+        ..Default::default()
     };
-    let backup_1 = sync_config("test", &config)?.unwrap();
+    let backup_1 = sync_config("sync_one_copy", &config)?
+        .into_backup()
+        .unwrap();
     assert!(backup_1.exists());
-    let backup_2 = sync_config("test", &config)?.unwrap();
+    wait_for_next_name();
+    let backup_2 = sync_config("sync_one_copy", &config)?
+        .into_backup()
+        .unwrap();
     assert!(backup_2.exists());
-    // The second backup should have cleaned up the first
+    // The second backup should have cleaned up the first, along with its checksum
     assert!(!backup_1.exists());
+    assert!(!sidecar(&backup_1, ".sha256").exists());
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_sync_removes_partials() -> Result<(), Error> {
+    setup_test_env();
+    let dir = Path::new("/tmp/backer-upper-partials/");
+    let _ = std::fs::remove_dir_all(dir);
+    std::fs::create_dir_all(dir).unwrap();
+    let config = Config {
+        globs: vec!["/tmp/backer-upper/*".to_string()],
+        dir: dir.to_string_lossy().into_owned(),
+        format: "partials_%Y-%m-%d_%H:%M:%S.tar.gz".to_string(),
+        interval: "0 seconds".to_string(),
+        ..Default::default()
+    };
+    // Pretend a previous sync died while writing its archive, hours after it started
+    let interrupted = dir.join(format!(
+        "{}.partial",
+        (chrono::Utc::now() - chrono::Duration::hours(3)).format(&config.format)
+    ));
+    std::fs::write(&interrupted, "half an archive").unwrap();
+    wait_for_next_name();
+    let backup = sync_config("sync_removes_partials", &config)?
+        .into_backup()
+        .unwrap();
+    assert!(backup.exists());
+    assert!(!sidecar(&backup, ".partial").exists());
+    assert!(!interrupted.exists());
+    // Partial files are left until the next backup is taken, so skipping doesn't list the dir
+    std::fs::write(&interrupted, "half an archive").unwrap();
+    let skipping = Config {
+        interval: "1 day".to_string(),
+        ..config.clone()
+    };
+    assert!(matches!(
+        sync_config("sync_removes_partials", &skipping)?,
+        Outcome::Skipped { .. }
+    ));
+    assert!(interrupted.exists());
+    wait_for_next_name();
+    sync_config("sync_removes_partials", &config)?;
+    assert!(!interrupted.exists());
     Ok(())
 }
 
 #[test]
 #[serial]
-fn test_sync_two_copies() -> Result<(), clap::error::Error> {
+fn test_sync_retention() -> Result<(), Error> {
+    setup_test_env();
+    std::fs::create_dir_all("/tmp/backer-upper-sync/").unwrap();
+    let config = Config {
+        globs: vec!["/tmp/backer-upper/*".to_string()],
+        dir: "/tmp/backer-upper-sync/".to_string(),
+        format: "test_sync_retention_%Y-%m-%d_%H:%M:%S.tar.gz".to_string(),
+        interval: "0 seconds".to_string(),
+        copies: Some(1),
+        retention: Retention {
+            keep_within: Some("1 hour".to_string()),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    // The retention table survives a round trip through a config file
+    let file = Path::new("/tmp/backer-upper-retention.toml");
+    write_config_file(
+        &ConfigCollection::from_config("sync_retention", config.clone()),
+        file,
+    )?;
+    assert_eq!(
+        read_config_file(file)?.configs["sync_retention"].retention,
+        config.retention
+    );
+    let backup_1 = sync_config("sync_retention", &config)?
+        .into_backup()
+        .unwrap();
+    wait_for_next_name();
+    let backup_2 = sync_config("sync_retention", &config)?
+        .into_backup()
+        .unwrap();
+    // Only one copy is wanted, but both are within the hour
+    assert!(backup_1.exists());
+    assert!(backup_2.exists());
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_prune() -> Result<(), Error> {
+    setup_test_env();
+    let dir = Path::new("/tmp/backer-upper-prune/");
+    let _ = std::fs::remove_dir_all(dir);
+    std::fs::create_dir_all(dir).unwrap();
+    let config = Config {
+        globs: vec!["/tmp/backer-upper/*".to_string()],
+        dir: dir.to_string_lossy().into_owned(),
+        format: "prune_%Y-%m-%d_%H:%M:%S.tar.gz".to_string(),
+        interval: "0 seconds".to_string(),
+        ..Default::default()
+    };
+    let backup_1 = sync_config("prune", &config)?.into_backup().unwrap();
+    wait_for_next_name();
+    let backup_2 = sync_config("prune", &config)?.into_backup().unwrap();
+    let config = Config {
+        copies: Some(1),
+        ..config
+    };
+    // A preview removes nothing
+    let decisions = prune_config("prune", &config, false)?.unwrap();
+    assert_eq!(
+        decisions
+            .iter()
+            .map(|decision| decision.keep)
+            .collect::<Vec<bool>>(),
+        vec![true, false]
+    );
+    assert!(backup_1.exists());
+    prune_config("prune", &config, true)?;
+    assert!(!backup_1.exists());
+    assert!(!sidecar(&backup_1, ".sha256").exists());
+    assert!(backup_2.exists());
+    let catalog = Catalog::load(&Destination::Local(dir.join("catalog.json")))?;
+    assert_eq!(catalog.backups("prune").len(), 1);
+    // Without copies or retention, every backup is kept
+    let config = Config {
+        copies: None,
+        ..config
+    };
+    assert_eq!(prune_config("prune", &config, false)?, None);
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_prune_continues_after_failure() -> Result<(), Error> {
+    setup_test_env();
+    let dir = Path::new("/tmp/backer-upper-prune-failure/");
+    let _ = std::fs::remove_dir_all(dir);
+    std::fs::create_dir_all(dir).unwrap();
+    let good = Config {
+        globs: vec!["/tmp/backer-upper/*".to_string()],
+        dir: dir.to_string_lossy().into_owned(),
+        format: "prune_failure_%Y-%m-%d_%H:%M:%S.tar.gz".to_string(),
+        interval: "0 seconds".to_string(),
+        ..Default::default()
+    };
+    let backup_1 = sync_config("b-good", &good)?.into_backup().unwrap();
+    wait_for_next_name();
+    sync_config("b-good", &good)?;
+    let good = Config {
+        copies: Some(1),
+        ..good
+    };
+    let bad = Config {
+        dir: dir.join("does-not-exist").to_string_lossy().into_owned(),
+        ..good.clone()
+    };
+    let mut configs = ConfigCollection::from_config("a-bad", bad);
+    configs.configs.insert("b-good".to_string(), good);
+    let file = Path::new("/tmp/backer-upper-prune-failure.toml");
+    write_config_file(&configs, file)?;
+    assert!(matches!(
+        prune(file, &None, true),
+        Err(Error::Prune {
+            failed: 1,
+            total: 2
+        })
+    ));
+    // The good config was pruned even though the bad one was first
+    assert!(!backup_1.exists());
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_sync_already_running() -> Result<(), Error> {
+    setup_test_env();
+    let dir = Path::new("/tmp/backer-upper-lock/");
+    let _ = std::fs::remove_dir_all(dir);
+    std::fs::create_dir_all(dir).unwrap();
+    let config = Config {
+        globs: vec!["/tmp/backer-upper/*".to_string()],
+        dir: dir.to_string_lossy().into_owned(),
+        format: "lock_%Y-%m-%d_%H:%M:%S.tar.gz".to_string(),
+        interval: "0 seconds".to_string(),
+        ..Default::default()
+    };
+    let lock = Lock::acquire(&None, dir, "other")?;
+    match sync_config("lock", &config)? {
+        Outcome::AlreadyRunning { holder } => {
+            assert_eq!(holder.section, "other");
+            assert_eq!(holder.pid, std::process::id());
+        }
+        outcome => panic!("synced while locked: {:?}", outcome),
+    }
+    assert!(matches!(
+        prune_config("lock", &config, true),
+        Err(Error::Locked(..))
+    ));
+    assert!(
+        Catalog::load(&Destination::Local(dir.join("catalog.json")))?
+            .backups("lock")
+            .is_empty()
+    );
+    drop(lock);
+    assert!(sync_config("lock", &config)?.into_backup().is_some());
+    assert!(!dir.join(LOCK_FILE).exists());
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_status() -> Result<(), Error> {
+    setup_test_env();
+    let dir = Path::new("/tmp/backer-upper-status/");
+    let _ = std::fs::remove_dir_all(dir);
+    std::fs::create_dir_all(dir).unwrap();
+    let config = Config {
+        globs: vec!["/tmp/backer-upper/*".to_string()],
+        dir: dir.to_string_lossy().into_owned(),
+        format: "status_%Y-%m-%d_%H:%M:%S.tar.gz".to_string(),
+        interval: "1 hour".to_string(),
+        copies: Some(2),
+        ..Default::default()
+    };
+    let now = chrono::Utc::now();
+    let status = status_config("status", &config, now)?;
+    assert_eq!(status.state, State::Critical);
+    assert_eq!(status.last_backup, None);
+    assert_eq!(status.next_run, now);
+    sync_config("status", &config)?;
+    let now = chrono::Utc::now();
+    let status = status_config("status", &config, now)?;
+    assert_eq!(status.state, State::Ok);
+    assert_eq!(status.backups, 1);
+    assert!(status.total_size > 0);
+    let last_backup = status.last_backup.unwrap();
+    assert_eq!(status.next_run, last_backup + chrono::Duration::hours(1));
+    // Hours later, with backups missed in between
+    let status = status_config("status", &config, now + chrono::Duration::hours(3))?;
+    assert_eq!(status.state, State::Warn);
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_sync_two_copies() -> Result<(), Error> {
     setup_test_env();
     std::fs::create_dir_all("/tmp/backer-upper-sync/").unwrap();
     let config = Config {
         globs: vec!["/tmp/backer-upper/*".to_string()],
         gpg_id: Some("test@chiquit.ooo".to_string()),
-        host: None,
         dir: "/tmp/backer-upper-sync/".to_string(),
         format: "test_sync_copies_%Y-%m-%d_%H:%M:%S.tar.gz.gpg".to_string(),
         // Always run
         interval: "0 seconds".to_string(),
         copies: Some(2),
+        ..Default::default()
     };
-    let backup_1 = sync_config("test", &config)?.unwrap();
+    let backup_1 = sync_config("sync_two_copies", &config)?
+        .into_backup()
+        .unwrap();
     assert!(backup_1.exists());
-    let backup_2 = sync_config("test", &config)?.unwrap();
+    wait_for_next_name();
+    let backup_2 = sync_config("sync_two_copies", &config)?
+        .into_backup()
+        .unwrap();
     assert!(backup_2.exists());
     assert!(backup_1.exists());
-    let backup_3 = sync_config("test", &config)?.unwrap();
+    wait_for_next_name();
+    let backup_3 = sync_config("sync_two_copies", &config)?
+        .into_backup()
+        .unwrap();
     assert!(backup_3.exists());
     assert!(backup_2.exists());
     assert!(!backup_1.exists());
     Ok(())
 }
+
+#[test]
+#[serial]
+fn test_sync_continues_after_failure() -> Result<(), Error> {
+    setup_test_env();
+    std::fs::create_dir_all("/tmp/backer-upper-sync/").unwrap();
+    let good = Config {
+        globs: vec!["/tmp/backer-upper/*".to_string()],
+        gpg_id: None,
+        dir: "/tmp/backer-upper-sync/".to_string(),
+        format: "test_sync_failure_%Y-%m-%d_%H:%M:%S.tar.gz".to_string(),
+        interval: "0 seconds".to_string(),
+        copies: Some(1),
+        ..Default::default()
+    };
+    let bad = Config {
+        dir: "/tmp/backer-upper-sync/does-not-exist/".to_string(),
+        ..good.clone()
+    };
+    let mut configs = ConfigCollection::from_config("a-bad", bad);
+    configs.configs.insert("b-good".to_string(), good.clone());
+    let file = Path::new("/tmp/backer-upper-sync.toml");
+    write_config_file(&configs, file)?;
+    assert!(matches!(
+        sync(file, false),
+        Err(Error::Sync {
+            failed: 1,
+            total: 2
+        })
+    ));
+    // The good config was synced even though the bad one was first
+    assert!(matches!(
+        sync_config(
+            "b-good",
+            &Config {
+                interval: "1 hour".to_string(),
+                ..good
+            }
+        )?,
+        Outcome::Skipped { .. }
+    ));
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_backup_readable_by_tar() -> Result<(), Error> {
+    setup_test_env();
+    backup(&["*".to_string()], Path::new("/tmp/backup.tar.gz"), &None)?;
+    let listing = run(Command::new("tar").args(["-tzf", "/tmp/backup.tar.gz"]))?;
+    assert_eq!(
+        listing.lines().collect::<Vec<&str>>(),
+        vec![
+            "tmp/backer-upper/a.txt",
+            "tmp/backer-upper/b.txt",
+            "tmp/backer-upper/dir",
+            "tmp/backer-upper/dir/c.txt",
+            "tmp/backer-upper/dir/d.txt",
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_backup_encrypted_without_intermediate() -> Result<(), Error> {
+    setup_test_env();
+    let intermediate = Path::new("/tmp/backup.tar.gz");
+    if intermediate.exists() {
+        std::fs::remove_file(intermediate).unwrap();
+    }
+    backup(
+        &["*".to_string()],
+        Path::new("/tmp/backup.tar.gz.gpg"),
+        &Some("test@chiquit.ooo".to_string()),
+    )?;
+    assert!(!intermediate.exists());
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_backup_unknown_gpg_id() {
+    setup_test_env();
+    assert!(matches!(
+        backup(
+            &["*".to_string()],
+            Path::new("/tmp/backup.tar.gz.gpg"),
+            &Some("nobody@chiquit.ooo".to_string()),
+        ),
+        Err(Error::Gpg(_))
+    ));
+}
+
+#[test]
+#[serial]
+fn test_contents() -> Result<(), Error> {
+    setup_test_env();
+    let backup_file = Path::new("/tmp/backup.tar.gz.gpg");
+    let gpg_id = Some("test@chiquit.ooo".to_string());
+    backup(&["/tmp/backer-upper/*".to_string()], backup_file, &gpg_id)?;
+    let entries = list_contents(backup_file, &["tmp/backer-upper/dir".to_string()], &gpg_id)?;
+    let paths: Vec<&Path> = entries.iter().map(|entry| entry.path.as_path()).collect();
+    assert_eq!(
+        paths,
+        vec![
+            Path::new("tmp/backer-upper/dir"),
+            Path::new("tmp/backer-upper/dir/c.txt"),
+            Path::new("tmp/backer-upper/dir/d.txt"),
+        ]
+    );
+    assert_eq!(entries[0].kind, Kind::Dir);
+    assert_eq!(entries[1].size, "dir/c.txt".len() as u64);
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_restore_to_target() -> Result<(), Error> {
+    setup_test_env();
+    let backup_file = Path::new("/tmp/backup.tar.gz.gpg");
+    let gpg_id = Some("test@chiquit.ooo".to_string());
+    backup(&["/tmp/backer-upper/*".to_string()], backup_file, &gpg_id)?;
+    let target = Path::new("/tmp/backer-upper-target/");
+    if target.exists() {
+        std::fs::remove_dir_all(target).unwrap();
+    }
+    sanitize_test_env();
+    restore_to(
+        backup_file,
+        &None,
+        &gpg_id,
+        &Target {
+            strip_prefix: Some(root()),
+            ..Target::new(target)
+        },
+    )?;
+    // The live files are untouched
+    assert_no_files(&["a.txt", "b.txt", "dir/c.txt", "dir/d.txt"]);
+    assert_eq!(
+        std::fs::read_to_string(target.join("dir/c.txt")).unwrap(),
+        "dir/c.txt"
+    );
+    assert!(target.join("a.txt").exists());
+    // Without a prefix to strip, files keep their full paths under the target
+    restore_to(
+        backup_file,
+        &Some(vec!["/tmp/backer-upper/b.txt".to_string()]),
+        &gpg_id,
+        &Target::new(target),
+    )?;
+    assert!(target.join("tmp/backer-upper/b.txt").exists());
+    assert!(!target.join("tmp/backer-upper/a.txt").exists());
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_restore_overwrite_policies() -> Result<(), Error> {
+    setup_test_env();
+    let backup_file = Path::new("/tmp/backup.tar.gz");
+    backup(&["/tmp/backer-upper/*".to_string()], backup_file, &None)?;
+    let a = root().join("a.txt");
+    std::fs::write(&a, "local").unwrap();
+    let restore_with = |overwrite| {
+        restore_to(
+            backup_file,
+            &None,
+            &None,
+            &Target {
+                overwrite,
+                ..Target::new(Path::new("/"))
+            },
+        )
+    };
+
+    let report = restore_with(Overwrite::Never)?;
+    assert_eq!(std::fs::read_to_string(&a).unwrap(), "local");
+    assert!(report.skipped.contains(&a));
+    assert!(report.replaced.is_empty());
+
+    // The local file is newer than the one in the backup
+    let report = restore_with(Overwrite::IfNewer)?;
+    assert_eq!(std::fs::read_to_string(&a).unwrap(), "local");
+    assert!(report.skipped.contains(&a));
+
+    let report = restore_with(Overwrite::Backup)?;
+    assert_eq!(std::fs::read_to_string(&a).unwrap(), "a.txt");
+    let orig = root().join("a.txt.orig");
+    assert_eq!(std::fs::read_to_string(&orig).unwrap(), "local");
+    assert!(report.preserved.contains(&(a.clone(), orig)));
+
+    let report = restore_with(Overwrite::Always)?;
+    assert!(report.replaced.contains(&a));
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_restore_dry_run_overwrite_policies() -> Result<(), Error> {
+    setup_test_env();
+    let backup_file = Path::new("/tmp/backup.tar.gz");
+    backup(&["/tmp/backer-upper/*".to_string()], backup_file, &None)?;
+    let a = root().join("a.txt");
+    let b = root().join("b.txt");
+    std::fs::write(&a, "local").unwrap();
+    std::fs::remove_file(&b).unwrap();
+    let plan_with = |overwrite| -> Result<Vec<Step>, Error> {
+        let mut plan = plan_restore(
+            backup_file,
+            &None,
+            &None,
+            &Target {
+                overwrite,
+                ..Target::new(Path::new("/"))
+            },
+        )?;
+        assert_eq!(plan.len(), 1);
+        Ok(plan.remove(0).1)
+    };
+    let step = |path: &Path, action| Step::Restore(path.to_path_buf(), action);
+
+    // A missing file is restored whatever the policy
+    for overwrite in [
+        Overwrite::Always,
+        Overwrite::Never,
+        Overwrite::IfNewer,
+        Overwrite::Backup,
+        Overwrite::Ask,
+    ] {
+        let plan = plan_with(overwrite)?;
+        assert!(plan.contains(&step(&b, Action::Restore)), "{:?}", overwrite);
+        assert!(
+            plan.contains(&step(&root().join("dir"), Action::Merge)),
+            "{:?}",
+            overwrite
+        );
+    }
+    assert!(plan_with(Overwrite::Always)?.contains(&step(&a, Action::Replace)));
+    assert!(plan_with(Overwrite::Never)?.contains(&step(&a, Action::Skip)));
+    // The local file is newer than the one in the backup
+    assert!(plan_with(Overwrite::IfNewer)?.contains(&step(&a, Action::Skip)));
+    assert!(plan_with(Overwrite::Backup)?
+        .contains(&step(&a, Action::Preserve(root().join("a.txt.orig")))));
+    assert!(plan_with(Overwrite::Ask)?.contains(&step(&a, Action::Ask)));
+
+    // Nothing was actually restored
+    assert_eq!(std::fs::read_to_string(&a).unwrap(), "local");
+    assert!(!b.exists());
+    assert!(!root().join("a.txt.orig").exists());
+    Ok(())
+}
+
+fn sidecar(backup: &Path, suffix: &str) -> PathBuf {
+    Destination::Local(backup.to_path_buf())
+        .sidecar(suffix)
+        .path()
+        .to_path_buf()
+}
+
+#[test]
+#[serial]
+fn test_backup_checksum() -> Result<(), Error> {
+    setup_test_env();
+    let backup_file = Path::new("/tmp/backup.tar.gz.gpg");
+    backup(
+        &["/tmp/backer-upper/*".to_string()],
+        backup_file,
+        &Some("test@chiquit.ooo".to_string()),
+    )?;
+    // The checksum can be checked by hand too
+    run(Command::new("sha256sum")
+        .arg("-c")
+        .arg(sidecar(backup_file, ".sha256"))
+        .current_dir("/tmp"))
+    .unwrap();
+    // Bit rot is noticed before even trying to decrypt
+    let mut contents = std::fs::read(backup_file).unwrap();
+    contents[0] ^= 0xff;
+    std::fs::write(backup_file, &contents).unwrap();
+    assert!(matches!(
+        verify(backup_file, &Some("test@chiquit.ooo".to_string())),
+        Err(Error::Corrupt(_, _))
+    ));
+    Ok(())
+}
+
+fn incremental_config(format: &str) -> Config {
+    Config {
+        globs: vec!["/tmp/backer-upper/*".to_string()],
+        gpg_id: Some("test@chiquit.ooo".to_string()),
+        dir: "/tmp/backer-upper-sync/".to_string(),
+        format: format.to_string(),
+        // Always run
+        interval: "0 seconds".to_string(),
+        incremental: Some(2),
+        ..Default::default()
+    }
+}
+
+fn load_snapshot(backup: &Path) -> Snapshot {
+    Snapshot::load(
+        &Destination::Local(backup.to_path_buf()),
+        &Some("test@chiquit.ooo".to_string()),
+    )
+    .unwrap()
+    .unwrap()
+}
+
+#[test]
+#[serial]
+fn test_sync_incremental_and_restore() -> Result<(), Error> {
+    setup_test_env();
+    std::fs::create_dir_all("/tmp/backer-upper-sync/").unwrap();
+    let config = incremental_config("test_incremental_%Y-%m-%d_%H:%M:%S.tar.gz.gpg");
+    let full = sync_config("sync_incremental_and_restore", &config)?
+        .into_backup()
+        .unwrap();
+    assert_eq!(load_snapshot(&full).depth, 0);
+    // Change, delete and add some files
+    std::fs::write(root().join("a.txt"), "changed").unwrap();
+    std::fs::remove_file(root().join("b.txt")).unwrap();
+    test_file(&root(), "e.txt");
+    wait_for_next_name();
+    let incremental = sync_config("sync_incremental_and_restore", &config)?
+        .into_backup()
+        .unwrap();
+    let snapshot = load_snapshot(&incremental);
+    assert_eq!(snapshot.depth, 1);
+    assert_eq!(
+        snapshot.parent,
+        Some(full.file_name().unwrap().to_str().unwrap().to_string())
+    );
+    assert_eq!(snapshot.deleted, vec![root().join("b.txt")]);
+    sanitize_test_env();
+    restore(&incremental, &None, &Some("test@chiquit.ooo".to_string()))?;
+    assert_files(&["a.txt", "dir/c.txt", "dir/d.txt", "e.txt"]);
+    assert_no_files(&["b.txt"]);
+    assert_eq!(
+        std::fs::read_to_string(root().join("a.txt")).unwrap(),
+        "changed"
+    );
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_sync_incremental_keeps_chain() -> Result<(), Error> {
+    setup_test_env();
+    std::fs::create_dir_all("/tmp/backer-upper-sync/").unwrap();
+    let config = Config {
+        copies: Some(1),
+        ..incremental_config("test_incremental_chain_%Y-%m-%d_%H:%M:%S.tar.gz.gpg")
+    };
+    let backup_1 = sync_config("sync_incremental_keeps_chain", &config)?
+        .into_backup()
+        .unwrap();
+    wait_for_next_name();
+    let backup_2 = sync_config("sync_incremental_keeps_chain", &config)?
+        .into_backup()
+        .unwrap();
+    wait_for_next_name();
+    let backup_3 = sync_config("sync_incremental_keeps_chain", &config)?
+        .into_backup()
+        .unwrap();
+    assert_eq!(load_snapshot(&backup_3).depth, 2);
+    // The incremental backups still need the full backup
+    assert!(backup_1.exists());
+    assert!(backup_2.exists());
+    wait_for_next_name();
+    let backup_4 = sync_config("sync_incremental_keeps_chain", &config)?
+        .into_backup()
+        .unwrap();
+    // Only two incremental backups are allowed, so this is a new full backup
+    assert_eq!(load_snapshot(&backup_4).depth, 0);
+    assert!(!backup_1.exists());
+    assert!(!backup_2.exists());
+    assert!(!backup_3.exists());
+    assert!(!Path::new(&format!("{}.snapshot", backup_1.display())).exists());
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_sync_repository_and_restore() -> Result<(), Error> {
+    setup_test_env();
+    let dir = Path::new("/tmp/backer-upper-repository/");
+    if dir.exists() {
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+    std::fs::create_dir_all(dir).unwrap();
+    let config = Config {
+        globs: vec!["/tmp/backer-upper/*".to_string()],
+        gpg_id: Some("test@chiquit.ooo".to_string()),
+        dir: dir.to_str().unwrap().to_string(),
+        format: "test_repository_%Y-%m-%d_%H:%M:%S.json.gpg".to_string(),
+        // Always run
+        interval: "0 seconds".to_string(),
+        copies: Some(1),
+        repository: true,
+        ..Default::default()
+    };
+    let backup_1 = sync_config("sync_repository_and_restore", &config)?
+        .into_backup()
+        .unwrap();
+    std::fs::write(root().join("a.txt"), "changed").unwrap();
+    wait_for_next_name();
+    let backup_2 = sync_config("sync_repository_and_restore", &config)?
+        .into_backup()
+        .unwrap();
+    assert!(!backup_1.exists());
+    // Only the chunks for the current versions of the files are left
+    let chunks: usize = std::fs::read_dir(dir.join("chunks"))
+        .unwrap()
+        .map(|prefix| prefix.unwrap())
+        .filter(|prefix| prefix.file_name() != "refs")
+        .map(|prefix| std::fs::read_dir(prefix.path()).unwrap().count())
+        .sum();
+    assert_eq!(chunks, 4);
+    let entries = list_contents(&backup_2, &[], &config.gpg_id)?;
+    assert_eq!(entries.len(), 5);
+    sanitize_test_env();
+    restore(&backup_2, &None, &Some("test@chiquit.ooo".to_string()))?;
+    assert_files(&["a.txt", "b.txt", "dir/c.txt", "dir/d.txt"]);
+    assert_eq!(
+        std::fs::read_to_string(root().join("a.txt")).unwrap(),
+        "changed"
+    );
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_sync_remote_repository() {
+    let config = Config {
+        host: Some("example.com".to_string()),
+        repository: true,
+        ..Default::default()
+    };
+    assert!(matches!(
+        sync_config("sync_remote_repository", &config),
+        Err(Error::InvalidConfig(_))
+    ));
+}